@@ -0,0 +1,237 @@
+use banmanager::Ban;
+use chrono::UTC;
+use duration;
+use modes::{ModeChange, ModeQueue};
+use std::ascii::StrAsciiExt;
+use std::mem;
+
+/// Where Q takes commands. Its replies come back as NOTICEs from plain "Q".
+pub static Q_ADDRESS: &'static str = "Q@CServe.quakenet.org";
+pub static Q_NICK: &'static str = "Q";
+
+/// Who actually puts a channel's bans in place and takes them off again.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub enum BackendKind {
+  /// We set +b ourselves, and lift it when the timer runs out.
+  ModeBackend,
+  /// QuakeNet's Q does it, with TEMPBAN, PERMBAN and BANDEL,
+  /// so the bans stick and expire even while we're offline.
+  QBackend
+}
+
+pub trait BanBackend {
+  /// Puts a ban in place, until it expires (or for good, if it has no timer).
+  fn set(&mut self, ban: &Ban);
+  /// Takes a ban off early.
+  fn lift(&mut self, ban: &Ban);
+  /// A ban's timer changed. Only matters if the backend does its own timing.
+  fn update(&mut self, _: &Ban) {}
+  /// A ban's timer ran out. Lifts it, unless the backend does that by itself.
+  fn expired(&mut self, ban: &Ban) {
+    self.lift(ban)
+  }
+}
+
+/// Bans we set with channel modes, queued up to go out several to a line.
+pub struct ModeBans {
+  pub queue: ModeQueue
+}
+
+impl ModeBans {
+  fn set_mode(&mut self, ban: &Ban, adding: bool) {
+    let (mode, target) = ban.mode_target();
+    self.queue.push(ban.channel(), ModeChange { adding: adding, mode: mode, param: Some(target.to_string()) });
+  }
+}
+
+impl BanBackend for ModeBans {
+  fn set(&mut self, ban: &Ban) {
+    self.set_mode(ban, true);
+  }
+  fn lift(&mut self, ban: &Ban) {
+    self.set_mode(ban, false);
+  }
+}
+
+/// Something we asked Q to do, while we wait to hear back.
+pub struct QRequest {
+  pub command: &'static str,
+  pub channel: String,
+  pub mask: String
+}
+
+/// What Q said about a request.
+pub enum QReply {
+  QDone(QRequest),
+  /// Q refused, and why, e.g. "You do not have sufficient access on #chan to use tempban."
+  QFailed(QRequest, String)
+}
+
+impl QRequest {
+  /// Does a reply from Q (lowercased) mention this request's channel and mask?
+  fn mentioned_in(&self, text: &str) -> (bool, bool) {
+    (text.contains(self.channel.as_slice().to_ascii_lower().as_slice()),
+     text.contains(self.mask.as_slice().to_ascii_lower().as_slice()))
+  }
+}
+
+/// Bans handed to Q, and what we're still waiting to hear back about, oldest first.
+pub struct QBans {
+  outbox: Vec<String>,
+  waiting: Vec<QRequest>
+}
+
+impl QBans {
+  pub fn new() -> QBans {
+    QBans { outbox: Vec::new(), waiting: Vec::new() }
+  }
+  fn send(&mut self, command: &'static str, ban: &Ban, rest: String) {
+    self.outbox.push(format!("{} {} {}{}", command, ban.channel(), ban.mask(), rest));
+    self.waiting.push(QRequest {
+      command: command,
+      channel: ban.channel().to_string(),
+      mask: ban.mask().to_string()
+    });
+  }
+  /// Are we still waiting to hear whether Q did something about a mask?
+  pub fn is_waiting(&self, channel: &str, mask: &str) -> bool {
+    self.waiting.iter().any(|request| request.channel.as_slice() == channel && request.mask.as_slice() == mask)
+  }
+  /// Messages for Q since we last asked.
+  pub fn take_outbox(&mut self) -> Vec<String> {
+    mem::replace(&mut self.outbox, Vec::new())
+  }
+  /// Reads a NOTICE from Q. "Done." doesn't say what's done, but Q answers in order,
+  /// so it's the oldest request. Anything else is a refusal, and has to name the channel
+  /// or mask of something we asked, preferably both; otherwise it's not about our bans
+  /// (e.g. it's about our AUTH) and we get None.
+  pub fn reply(&mut self, text: &str) -> Option<QReply> {
+    if text.trim() == "Done." {
+      return self.waiting.remove(0).map(|request| QDone(request));
+    }
+    let lower = text.to_ascii_lower();
+    let idx = self.waiting.iter().position(|request| request.mentioned_in(lower.as_slice()) == (true, true))
+      .or_else(|| self.waiting.iter().position(|request| {
+        let (channel, mask) = request.mentioned_in(lower.as_slice());
+        channel || mask
+      }));
+    match idx {
+      Some(idx) => self.waiting.remove(idx).map(|request| QFailed(request, text.to_string())),
+      None => None
+    }
+  }
+}
+
+impl BanBackend for QBans {
+  fn set(&mut self, ban: &Ban) {
+    let reason = if ban.reason().is_empty() { String::new() } else { format!(" {}", ban.reason()) };
+    match ban.expires() {
+      Some(expires) => {
+        let length = duration::format(expires - UTC::now());
+        self.send("TEMPBAN", ban, format!(" {}{}", length, reason))
+      },
+      None => self.send("PERMBAN", ban, reason)
+    }
+  }
+  fn lift(&mut self, ban: &Ban) {
+    self.send("BANDEL", ban, String::new());
+  }
+  /// Q replaces a ban's length when it's set again.
+  fn update(&mut self, ban: &Ban) {
+    self.set(ban);
+  }
+  /// Q lifts its own tempbans.
+  fn expired(&mut self, _: &Ban) {}
+}
+
+/// All the backends, so the BanManager can pick one per ban.
+pub struct Backends {
+  pub modes: ModeBans,
+  pub q: QBans
+}
+
+impl Backends {
+  pub fn new() -> Backends {
+    Backends {
+      modes: ModeBans { queue: ModeQueue::new() },
+      q: QBans::new()
+    }
+  }
+  pub fn get<'a>(&'a mut self, kind: BackendKind) -> &'a mut BanBackend {
+    match kind {
+      ModeBackend => &mut self.modes as &mut BanBackend,
+      QBackend => &mut self.q as &mut BanBackend
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use banmanager::{Ban, FullBan};
+  use chrono::Duration;
+  use super::{BanBackend, QBans, QDone, QFailed};
+
+  fn ban() -> Ban {
+    Ban::new("#test", "*!*@a".to_string(), FullBan, Duration::hours(1), "spam", "tester")
+  }
+
+  #[test]
+  fn q_commands() {
+    let mut q = QBans::new();
+    q.set(&ban());
+    q.lift(&ban());
+    let outbox = q.take_outbox();
+    // a second might have passed since the ban was made
+    assert!(outbox[0].as_slice().starts_with("TEMPBAN #test *!*@a "));
+    assert!(outbox[0].as_slice().ends_with(" spam"));
+    assert_eq!(outbox[1].as_slice(), "BANDEL #test *!*@a");
+  }
+
+  #[test]
+  fn q_replies() {
+    let mut q = QBans::new();
+    assert!(q.reply("You are now logged in as NoFunBot.").is_none());
+
+    q.set(&ban());
+    q.lift(&ban());
+    match q.reply("Done.") {
+      Some(QDone(request)) => assert_eq!(request.command, "TEMPBAN"),
+      _ => fail!("TEMPBAN should be done")
+    }
+    match q.reply("You do not have sufficient access on #test to use bandel.") {
+      Some(QFailed(request, why)) => {
+        assert_eq!(request.command, "BANDEL");
+        assert!(why.as_slice().contains("sufficient access"));
+      },
+      _ => fail!("BANDEL should have failed")
+    }
+    assert!(q.reply("Done.").is_none());
+  }
+
+  /// Refusals go with the request they're about, and other notices are left alone
+  #[test]
+  fn q_replies_matched() {
+    let mut q = QBans::new();
+    q.set(&ban());
+    q.set(&Ban::new("#other", "*!*@b".to_string(), FullBan, Duration::hours(1), "spam", "tester"));
+    q.lift(&Ban::new("#other", "*!*@c".to_string(), FullBan, Duration::hours(1), "spam", "tester"));
+
+    assert!(q.reply("Remember: NO-ONE from QuakeNet will ever ask for your password.").is_none());
+    match q.reply("Can't find a ban on *!*@C in #Other.") {
+      Some(QFailed(request, _)) => {
+        assert_eq!(request.command, "BANDEL");
+        assert_eq!(request.mask.as_slice(), "*!*@c");
+      },
+      _ => fail!("BANDEL should have failed")
+    }
+    match q.reply("You do not have sufficient access on #other to use tempban.") {
+      Some(QFailed(request, _)) => assert_eq!(request.mask.as_slice(), "*!*@b"),
+      _ => fail!("the #other TEMPBAN should have failed")
+    }
+    match q.reply("Done.") {
+      Some(QDone(request)) => assert_eq!(request.channel.as_slice(), "#test"),
+      _ => fail!("the #test TEMPBAN should be done")
+    }
+    assert!(q.reply("You do not have sufficient access on #test to use tempban.").is_none());
+  }
+}
@@ -1,66 +1,792 @@
-use chrono::{Duration, DateTime, UTC};
+use chrono::{Duration, DateTime, UTC, Offset};
 use irc::conn::{Conn,IRCCmd};
-use irc;
+use banbackend::{BanBackend, Backends, BackendKind, ModeBackend, QBackend, QDone, QFailed, Q_ADDRESS};
+use duration;
+use files;
+use modes::ModeChange;
+use serialize::json;
+use std::collections::{HashMap, HashSet};
+use std::io::File;
+use std::mem;
+
+/// What a ban actually does in the channel.
+#[deriving(Clone, PartialEq, Show)]
+pub enum BanKind {
+  /// +b mask
+  FullBan,
+  /// Lets them read but not talk. Holds the mode to set and what to set it to,
+  /// e.g. ('q', "*!*@host") or ('b', "~q:*!*@host"); see `ISupport::quiet`.
+  Quiet(char, String)
+}
+
+impl BanKind {
+  /// For log messages, e.g. "Banned fred"
+  pub fn verb(&self) -> &'static str {
+    match *self {
+      FullBan => "Banned",
+      Quiet(..) => "Quieted"
+    }
+  }
+  /// For log messages about bans that have to wait for ops, e.g. "Will ban fred"
+  pub fn pending_verb(&self) -> &'static str {
+    match *self {
+      FullBan => "Will ban",
+      Quiet(..) => "Will quiet"
+    }
+  }
+}
 
 pub struct Ban {
+  /// Handed out by the BanManager, so mods can refer to bans. Not kept across restarts.
+  id: uint,
   mask: String,
+  kind: BanKind,
   channel: String,
-  expires: DateTime<UTC>
+  /// None for bans that were set by somebody else, and that nobody's put a timer on.
+  expires: Option<DateTime<UTC>>,
+  reason: String,
+  set_by: String,
+  /// Who sets and lifts it: us, or services.
+  backend: BackendKind,
+
+  /// Loaded from the store, and waiting for us to join the channel
+  /// before we can lift or keep it.
+  awaiting_join: bool
 }
+
+/// How a ban looks on disk.
+#[deriving(Encodable, Decodable)]
+struct BanRecord {
+  mask: String,
+  /// Only set for quiets, see `BanKind`.
+  quiet: Option<(char, String)>,
+  channel: String,
+  expires: Option<i64>,
+  reason: String,
+  set_by: String,
+  /// Missing from stores written before there was a choice.
+  backend: Option<BackendKind>
+}
+
+/// How many times a mask has been banned from a channel, on disk.
+#[deriving(Encodable, Decodable)]
+struct StrikeRecord {
+  mask: String,
+  channel: String,
+  strikes: uint,
+  /// When the last one was, in seconds since the epoch.
+  /// Missing from stores written before strikes wore off.
+  last: Option<i64>
+}
+
+/// Past bans of a mask in a channel.
+struct Strikes {
+  count: uint,
+  /// When the latest one was. They all wear off once it's old enough, see `strike_reset`.
+  last: DateTime<UTC>
+}
+
+/// Where a ban sits in a channel's lists: the channel, the mode, and what it's set on,
+/// e.g. ("#chan", 'b', "*!*@host"). A quiet and a ban on the same mask are different entries.
+type BanKey = (String, char, String);
+
+fn ban_key(channel: &str, mode: char, target: &str) -> BanKey {
+  (channel.to_string(), mode, target.to_string())
+}
+
+#[deriving(Encodable, Decodable)]
+struct Store {
+  bans: Vec<BanRecord>,
+  strikes: Vec<StrikeRecord>
+}
+
 impl Ban {
-  pub fn new(channel: &str, user: &irc::User, length: Duration) -> Ban {
-    let banmask = format!("*!*@{}", String::from_utf8_lossy(user.host().expect("No hostname?")));
-    Ban { 
-      mask: banmask,
+  /// `mask` should come from `masks::mask_for`, or a mod.
+  pub fn new(channel: &str, mask: String, kind: BanKind, length: Duration, reason: &str, set_by: &str) -> Ban {
+    Ban {
+      id: 0,
+      mask: mask,
+      kind: kind,
       channel: channel.to_string(),
-      expires: UTC::now() + length
+      expires: Some(UTC::now() + length),
+      reason: reason.to_string(),
+      set_by: set_by.to_string(),
+      backend: ModeBackend,
+      awaiting_join: false
+    }
+  }
+  fn from_record(record: BanRecord) -> Ban {
+    Ban {
+      id: 0,
+      mask: record.mask,
+      kind: match record.quiet {
+        Some((mode, target)) => Quiet(mode, target),
+        None => FullBan
+      },
+      channel: record.channel,
+      expires: record.expires.map(|secs| UTC.timestamp(secs, 0)),
+      reason: record.reason,
+      set_by: record.set_by,
+      backend: record.backend.unwrap_or(ModeBackend),
+      awaiting_join: true
     }
   }
+  fn key(&self) -> BanKey {
+    let (mode, target) = self.mode_target();
+    ban_key(self.channel.as_slice(), mode, target)
+  }
+  fn to_record(&self) -> BanRecord {
+    BanRecord {
+      mask: self.mask.clone(),
+      quiet: match self.kind {
+        FullBan => None,
+        Quiet(mode, ref target) => Some((mode, target.clone()))
+      },
+      channel: self.channel.clone(),
+      expires: self.expires.map(|expires| expires.timestamp()),
+      reason: self.reason.clone(),
+      set_by: self.set_by.clone(),
+      backend: Some(self.backend)
+    }
+  }
+  pub fn id(&self) -> uint {
+    self.id
+  }
+  pub fn kind<'a>(&'a self) -> &'a BanKind {
+    &self.kind
+  }
+  pub fn channel<'a>(&'a self) -> &'a str {
+    self.channel.as_slice()
+  }
+  pub fn mask<'a>(&'a self) -> &'a str {
+    self.mask.as_slice()
+  }
+  pub fn set_by<'a>(&'a self) -> &'a str {
+    self.set_by.as_slice()
+  }
+  pub fn reason<'a>(&'a self) -> &'a str {
+    self.reason.as_slice()
+  }
+  pub fn backend(&self) -> BackendKind {
+    self.backend
+  }
+  pub fn expires(&self) -> Option<DateTime<UTC>> {
+    self.expires
+  }
   pub fn is_active(&self) -> bool {
     let curtime = UTC::now();
-    
-    self.expires >= curtime
+
+    self.expires.map_or(true, |expires| expires >= curtime)
   }
-  /// Updates the modes on the channel to reflect this ban.
-  pub fn update_usermode(&self, conn: &mut Conn) {
-    if self.is_active() {
-      conn.send_command(IRCCmd("MODE".into_maybe_owned()), [self.channel.as_bytes(), b"+b", self.mask.as_bytes()], false);
-    } else {
-      conn.send_command(IRCCmd("MODE".into_maybe_owned()), [self.channel.as_bytes(), b"-b", self.mask.as_bytes()], false);
+  /// The channel mode and parameter this ban is set with, e.g. ('b', "*!*@host")
+  pub fn mode_target<'a>(&'a self) -> (char, &'a str) {
+    match self.kind {
+      FullBan => ('b', self.mask.as_slice()),
+      Quiet(mode, ref target) => (mode, target.as_slice())
     }
   }
+  /// Does this ban take up a slot in a channel's list for any of `modes`?
+  fn in_list(&self, channel: &str, modes: &str) -> bool {
+    let (mode, _) = self.mode_target();
+    self.channel.as_slice() == channel && modes.contains_char(mode)
+  }
+  /// One line summary for mods, e.g. "#4 *!*@host in #chan, 3m left, set by NoFunBot: Stop spamming."
+  pub fn describe(&self) -> String {
+    let left = match self.expires {
+      Some(expires) => format!("{} left", duration::format(expires - UTC::now())),
+      None => "no timer".to_string()
+    };
+    let (_, target) = self.mode_target();
+    format!("#{} {}{} in {}, {}, set by {}{}",
+            self.id,
+            match self.kind { FullBan => "", Quiet(..) => "(quiet) " },
+            target,
+            self.channel,
+            left,
+            self.set_by,
+            if self.reason.is_empty() { String::new() } else { format!(": {}", self.reason) })
+  }
 }
 
+/// Fills in a kick message template, e.g.
+/// "Temp-banned for {length}: {reason}" -> "Temp-banned for 5m: This isn't Twitch chat."
+fn kick_message(template: &str, length: Duration, reason: &str) -> String {
+  template.replace("{length}", duration::format(length).as_slice())
+    .as_slice().replace("{reason}", reason)
+}
+
+/// How many free slots we try to keep in a channel's ban list, for the humans.
+static BANLIST_HEADROOM: uint = 2;
+
+/// Something we sent that needs ops.
+enum Action {
+  SetModes(Vec<ModeChange>),
+  /// nick, message
+  Kick(Vec<u8>, String)
+}
+
+/// An action we sent, until the server echoes it back or refuses it with 482.
+struct Sent {
+  channel: String,
+  action: Action,
+  at: DateTime<UTC>
+}
+
+/// Sent actions the server never answered are forgotten after this long,
+/// e.g. a +b it quietly dropped because the ban was already there.
+static SENT_TIMEOUT_SECS: i64 = 60;
+
 pub struct BanManager {
-  bans: Vec<Ban>,
-  ban_length: Duration
+  bans: HashMap<BanKey, Ban>,
+  ban_length: Duration,
+  /// Each repeat ban of the same mask lasts this many times longer than the last.
+  escalation: f64,
+  /// Past bans, by (channel, mask).
+  strikes: HashMap<(String, String), Strikes>,
+  /// Strikes are forgotten once a mask goes this long without being banned again.
+  strike_reset: Duration,
+  /// What to tell kicked users, see `kick`. None means don't kick.
+  kick_reason: Option<String>,
+  /// Quiet first-time offenders instead of banning them, where the network can.
+  quiet_first: bool,
+  /// Masks seen so far while the server lists a channel's bans, by channel.
+  syncing: HashMap<String, HashSet<String>>,
+  /// How full a channel's ban list can get: the list modes sharing the limit, and the limit.
+  list_limit: Option<(String, uint)>,
+  /// Things the mods should hear about, and the channel they're about; see `take_warnings`.
+  warnings: Vec<(String, String)>,
+  /// What actually sets and lifts bans, see `flush_modes`.
+  /// Which one a channel's new bans go through is up to the channel, see `IRCChannel::get_ban_backend`.
+  backends: Backends,
+  /// How many mode changes fit in a MODE line, from ISUPPORT.
+  modes_per_line: uint,
+  /// Kicks waiting for their bans to go out: channel, nick, and message.
+  kicks: Vec<(String, Vec<u8>, String)>,
+  /// Actions the server hasn't confirmed yet, oldest first.
+  sent: Vec<Sent>,
+  next_id: uint,
+  store: Path
 }
 impl BanManager {
-  pub fn new() -> BanManager {
-    BanManager { bans: Vec::new(), ban_length: Duration::minutes(5) }
+  /// Creates a ban manager, with any bans left over from last time.
+  pub fn new(store: Path, escalation: f64, strike_reset: Duration, kick_reason: Option<String>,
+             quiet_first: bool) -> BanManager {
+    let mut mgr = BanManager {
+      bans: HashMap::new(),
+      ban_length: Duration::minutes(5),
+      escalation: escalation,
+      strikes: HashMap::new(),
+      strike_reset: strike_reset,
+      kick_reason: kick_reason,
+      quiet_first: quiet_first,
+      syncing: HashMap::new(),
+      list_limit: None,
+      warnings: Vec::new(),
+      backends: Backends::new(),
+      modes_per_line: 3,
+      kicks: Vec::new(),
+      sent: Vec::new(),
+      next_id: 1,
+      store: store
+    };
+    mgr.load();
+    mgr
   }
   /// Unbans expired bans
-  pub fn update(&mut self, conn: &mut Conn) {
-    let now = UTC::now();
-    let expired_bans: Vec<uint> = { self.bans.iter().filter(|ban| !ban.is_active()).enumerate().map(|(id, _)| id).collect() };
-    for id in expired_bans.iter() {
-      self.unban(conn, *id)
+  pub fn update(&mut self) {
+    let stale = UTC::now() - Duration::seconds(SENT_TIMEOUT_SECS);
+    self.sent.retain(|sent| sent.at > stale);
+
+    let expired = self.take_expired(UTC::now());
+    if expired.is_empty() {
+      return;
+    }
+    for ban in expired.iter() {
+      info!("Ban on {} in {} expired", ban.mask, ban.channel);
+      self.backends.get(ban.backend).expired(ban);
+    }
+    self.save();
+  }
+  /// Removes bans that ran out before `now`, and returns them.
+  /// Bans waiting for us to rejoin their channel are left alone.
+  fn take_expired(&mut self, now: DateTime<UTC>) -> Vec<Ban> {
+    let expired = self.bans.iter()
+      .filter(|&(_, ban)| ban.expires.map_or(false, |expires| expires < now) && !ban.awaiting_join)
+      .map(|(key, _)| key.clone())
+      .collect();
+    self.take(expired)
+  }
+  /// Removes bans, and returns them oldest first.
+  fn take(&mut self, keys: Vec<BanKey>) -> Vec<Ban> {
+    let mut taken: Vec<Ban> = keys.iter().filter_map(|key| self.bans.pop(key)).collect();
+    taken.sort_by(|a, b| a.id.cmp(&b.id));
+    taken
+  }
+
+  /// We just joined a channel, so bans loaded from the store can be dealt with:
+  /// lift the ones that ran out while we were gone, and keep timing the rest.
+  pub fn restore(&mut self, channel: &str) {
+    let stale = self.bans.iter()
+      .filter(|&(_, ban)| ban.awaiting_join && ban.channel.as_slice() == channel && !ban.is_active())
+      .map(|(key, _)| key.clone())
+      .collect();
+    let stale = self.take(stale);
+    for (_, ban) in self.bans.mut_iter().filter(|&(_, ref ban)| ban.channel.as_slice() == channel) {
+      ban.awaiting_join = false;
+    }
+    if stale.is_empty() {
+      return;
+    }
+    for ban in stale.iter() {
+      info!("Lifting {} in {}, it expired while we were away", ban.mask, ban.channel);
+      self.backends.get(ban.backend).expired(ban);
+    }
+    self.save();
+  }
+
+  /// How many times a mask's been banned from a channel lately.
+  fn strikes(&self, channel: &str, mask: &str) -> uint {
+    match self.strikes.find(&(channel.to_string(), mask.to_string())) {
+      Some(strikes) if UTC::now() - strikes.last < self.strike_reset => strikes.count,
+      _ => 0
+    }
+  }
+  fn add_strike(&mut self, channel: &str, mask: &str) {
+    let count = self.strikes(channel, mask) + 1;
+    self.strikes.insert((channel.to_string(), mask.to_string()), Strikes { count: count, last: UTC::now() });
+  }
+  /// Takes back a strike for a ban that never went in.
+  fn remove_strike(&mut self, channel: &str, mask: &str) {
+    let key = (channel.to_string(), mask.to_string());
+    let none_left = match self.strikes.find_mut(&key) {
+      Some(strikes) => {
+        strikes.count -= 1;
+        strikes.count == 0
+      },
+      None => false
+    };
+    if none_left {
+      self.strikes.remove(&key);
     }
   }
 
-  /// Bans a nick. TODO: this can't extend existing bans
-  pub fn ban(&mut self, conn: &mut Conn, channel: &str, user: &irc::User) {
-    //conn.send_command(IRCCmd("KICK".into_maybe_owned()),
-    //  [channel.as_bytes(), nick.as_bytes(), b"Temp-banned"], true);
+  /// How long the next automatic ban of this mask will be,
+  /// given how many times it's been banned before.
+  pub fn next_ban_length(&self, channel: &str, mask: &str) -> Duration {
+    let strikes = self.strikes(channel, mask);
+    let secs = self.ban_length.num_seconds() as f64 * self.escalation.powi(strikes as i32);
+    // don't overflow on a really persistent troll
+    Duration::seconds(secs.min(Duration::days(365).num_seconds() as f64) as i64)
+  }
+
+  /// Should the next automatic punishment for this mask be a quiet, rather than a ban?
+  /// Only the first one is, if we're set up that way.
+  pub fn should_quiet(&self, channel: &str, mask: &str) -> bool {
+    self.quiet_first && self.strikes(channel, mask) == 0
+  }
+
+  /// Bans a mask for the automatic ban length, escalated for repeat offenders.
+  /// Returns how long they got.
+  pub fn ban(&mut self, channel: &str, mask: String, kind: BanKind, reason: &str, set_by: &str,
+             backend: BackendKind) -> Duration {
+    let length = self.next_ban_length(channel, mask.as_slice());
+    let ban = Ban::new(channel, mask, kind, length, reason, set_by);
+    self.apply(ban, backend);
+    length
+  }
+  /// Applies a ban made elsewhere, e.g. from a ticket.
+  /// If the mask is already banned in the channel, the existing ban is extended instead,
+  /// and that doesn't count as another strike. `backend` is the channel's.
+  pub fn apply(&mut self, mut ban: Ban, backend: BackendKind) {
+    // services only do full bans
+    if ban.kind == FullBan {
+      ban.backend = backend;
+    }
+
+    let key = ban.key();
+    if self.bans.contains_key(&key) {
+      let existing = self.bans.find_mut(&key).unwrap();
+      info!("{} is already banned in {}, extending", existing.mask, existing.channel);
+      // bans with no timer stay that way
+      if existing.expires.map_or(false, |old| ban.expires > Some(old)) {
+        existing.expires = ban.expires;
+      }
+      existing.reason = ban.reason;
+      existing.set_by = ban.set_by;
+      self.backends.get(existing.backend).update(existing);
+    } else {
+      self.add_strike(ban.channel.as_slice(), ban.mask.as_slice());
+      let (mode, _) = ban.mode_target();
+      // only warn about the first one held, so a raid doesn't flood the mods
+      let first_held = self.backends.modes.queue.held_count(ban.channel.as_slice()) == 0;
+      self.make_room(ban.channel.as_slice(), mode);
+      self.backends.get(ban.backend).set(&ban);
+      if !self.can_apply(ban.channel.as_slice(), &ban.kind, ban.backend) && first_held {
+        self.warnings.push((ban.channel.clone(),
+                            format!("I'm not opped in {}, so banning {} will have to wait until I am",
+                                    ban.channel, ban.mask)));
+      }
+      self.track(ban);
+    }
+    self.save();
+  }
+  /// Sets the ban list limit, from ISUPPORT.
+  pub fn set_list_limit(&mut self, limit: Option<(String, uint)>) {
+    self.list_limit = limit;
+  }
+  /// If a channel's list for `mode` is about full, lifts our bans that expire soonest
+  /// until there's room for one more. Warns if it can't.
+  fn make_room(&mut self, channel: &str, mode: char) {
+    let (modes, limit) = match self.list_limit {
+      Some((ref modes, limit)) if modes.as_slice().contains_char(mode) => (modes.clone(), limit),
+      _ => return
+    };
+    let modes = modes.as_slice();
 
-    let ban = Ban::new(channel, user, self.ban_length);
-    ban.update_usermode(conn);
-    
-    self.bans.push(ban);
+    loop {
+      let used = self.bans.values().filter(|ban| ban.in_list(channel, modes)).count();
+      if used + BANLIST_HEADROOM < limit {
+        return;
+      }
+      // only bans with a timer: they're coming off anyway, and humans' bans aren't ours to touch
+      let victim = self.bans.iter()
+        .filter(|&(_, ban)| ban.in_list(channel, modes) && ban.expires.is_some())
+        .min_by(|&(_, ban)| ban.expires)
+        .map(|(key, _)| key.clone());
+      match victim {
+        Some(key) => {
+          let ban = self.bans.pop(&key).unwrap();
+          self.warnings.push((channel.to_string(),
+                              format!("Ban list in {} is nearly full ({}/{}), lifting {} early to make room",
+                                      channel, used, limit, ban.mask)));
+          self.backends.get(ban.backend).lift(&ban);
+        },
+        None => {
+          self.warnings.push((channel.to_string(),
+                              format!("Ban list in {} is nearly full ({}/{}) and none of those bans are mine to lift!",
+                                      channel, used, limit)));
+          return;
+        }
+      }
+    }
+  }
+  /// The server said a channel's ban list is full (478), so a ban didn't stick.
+  /// Forget it, and the strike that came with it, and tell the mods.
+  pub fn list_full(&mut self, channel: &str, target: &str) {
+    let keys = self.bans.keys()
+      .filter(|&&(ref c, _, ref t)| c.as_slice() == channel && t.as_slice() == target)
+      .map(|key| key.clone())
+      .collect();
+    for ban in self.take(keys).iter() {
+      self.remove_strike(ban.channel.as_slice(), ban.mask.as_slice());
+    }
+    self.save();
+    self.warnings.push((channel.to_string(), format!("Couldn't set {} in {}: the ban list is full!", target, channel)));
   }
-  pub fn unban(&mut self, conn: &mut Conn, id: uint) {
-    let expired_ban = self.bans.remove(id).unwrap();
-    expired_ban.update_usermode(conn);
+  /// Warnings for the mods since we last asked, e.g. about full ban lists.
+  pub fn take_warnings(&mut self) -> Vec<(String, String)> {
+    mem::replace(&mut self.warnings, Vec::new())
+  }
+  /// Asks the server for a channel's ban list, so we know about bans we didn't set.
+  /// Replies come back through `sync_ban` and `end_sync`.
+  pub fn request_ban_list(&mut self, conn: &mut Conn, channel: &str) {
+    self.syncing.insert(channel.to_string(), HashSet::new());
+    conn.send_command(IRCCmd("MODE".into_maybe_owned()), [channel.as_bytes(), b"+b"], false);
+  }
+  /// One entry from the ban list (367).
+  pub fn sync_ban(&mut self, channel: &str, mask: &str, set_by: &str) {
+    match self.syncing.find_mut(&channel.to_string()) {
+      Some(seen) => { seen.insert(mask.to_string()); },
+      None => ()
+    }
+    self.ban_added(channel, mask, set_by);
+  }
+  /// End of the ban list (368). Any of our bans that weren't on it
+  /// were lifted while we weren't looking, so stop timing them.
+  /// Bans still on their way in aren't on it yet, so they stay.
+  pub fn end_sync(&mut self, channel: &str) {
+    let seen = match self.syncing.pop(&channel.to_string()) {
+      Some(seen) => seen,
+      None => return
+    };
+    let gone: Vec<BanKey> = self.bans.iter()
+      .filter(|&(_, ban)| {
+        ban.channel.as_slice() == channel && ban.kind == FullBan && !seen.contains_equiv(&ban.mask.as_slice()) &&
+          !self.in_flight(ban)
+      })
+      .map(|(key, _)| key.clone())
+      .collect();
+    if !gone.is_empty() {
+      info!("{} of our bans in {} were gone from the ban list", gone.len(), channel);
+      for key in gone.iter() {
+        self.bans.remove(key);
+      }
+    }
+    // covers the bans the list told us about, too
+    self.save();
+  }
+  /// Have we asked for a ban to be set, without hearing back yet?
+  /// It might be queued, sent and unconfirmed, or waiting on Q.
+  fn in_flight(&self, ban: &Ban) -> bool {
+    let (mode, target) = ban.mode_target();
+    let channel = ban.channel.as_slice();
+    self.backends.modes.queue.is_pending(channel, mode, target) ||
+      self.backends.q.is_waiting(channel, ban.mask.as_slice()) ||
+      self.sent.iter().any(|sent| sent.channel.as_slice() == channel && match sent.action {
+        SetModes(ref changes) => changes.iter().any(|change| {
+          change.adding && change.mode == mode && change.param.as_ref().map_or(false, |p| p.as_slice() == target)
+        }),
+        Kick(..) => false
+      })
+  }
+  /// Somebody set +b. If it's not one of ours, track it, with no timer.
+  /// Returns true if it was news to us.
+  pub fn ban_added(&mut self, channel: &str, mask: &str, set_by: &str) -> bool {
+    if self.bans.contains_key(&ban_key(channel, 'b', mask)) {
+      return false;
+    }
+    self.track(Ban {
+      id: 0,
+      mask: mask.to_string(),
+      kind: FullBan,
+      channel: channel.to_string(),
+      expires: None,
+      reason: String::new(),
+      set_by: set_by.to_string(),
+      backend: ModeBackend,
+      awaiting_join: false
+    });
+    // a ban list sync saves once at the end instead
+    if !self.syncing.contains_key(&channel.to_string()) {
+      self.save();
+    }
+    true
+  }
+  /// Somebody removed a ban (or a quiet). Stop timing it, and return it if we had it.
+  pub fn ban_removed(&mut self, channel: &str, mode: char, target: &str) -> Option<Ban> {
+    let removed = self.bans.pop(&ban_key(channel, mode, target));
+    if removed.is_some() {
+      self.save();
+    }
+    removed
+  }
+  fn track(&mut self, mut ban: Ban) {
+    ban.id = self.next_id;
+    self.next_id += 1;
+    self.bans.insert(ban.key(), ban);
+  }
+  /// Our bans, oldest first.
+  pub fn iter<'a>(&'a self) -> ::std::vec::MoveItems<&'a Ban> {
+    let mut bans: Vec<&Ban> = self.bans.values().collect();
+    bans.sort_by(|a, b| a.id.cmp(&b.id));
+    bans.move_iter()
+  }
+  /// Lifts a ban early, by id.
+  pub fn lift(&mut self, id: uint) -> Option<Ban> {
+    let key = match self.bans.iter().find(|&(_, ban)| ban.id == id) {
+      Some((key, _)) => key.clone(),
+      None => return None
+    };
+    let ban = self.bans.pop(&key).unwrap();
+    self.backends.get(ban.backend).lift(&ban);
+    self.save();
+    Some(ban)
+  }
+  /// Lifts a ban early, by mask. Tries even if it's not a ban we know about,
+  /// through the channel's `backend`.
+  pub fn lift_mask(&mut self, channel: &str, mask: &str, backend: BackendKind) -> Option<Ban> {
+    match self.bans.values().find(|b| b.channel.as_slice() == channel && b.mask.as_slice() == mask).map(|b| b.id) {
+      Some(id) => self.lift(id),
+      None => {
+        let mut ban = Ban::new(channel, mask.to_string(), FullBan, Duration::zero(), "", "");
+        ban.backend = backend;
+        self.backends.get(ban.backend).lift(&ban);
+        None
+      }
+    }
+  }
+  /// Makes a ban last longer, counting from when it would have expired,
+  /// or from now if it had no timer. Returns the new expiry.
+  pub fn extend(&mut self, id: uint, by: Duration) -> Option<DateTime<UTC>> {
+    let expires = match self.bans.mut_iter().find(|&(_, ref ban)| ban.id == id) {
+      Some((_, ban)) => {
+        let expires = ban.expires.unwrap_or(UTC::now()) + by;
+        ban.expires = Some(expires);
+        self.backends.get(ban.backend).update(ban);
+        expires
+      },
+      None => return None
+    };
+    self.save();
+    Some(expires)
+  }
+  /// Puts a timer on an existing ban, e.g. one a human set.
+  /// Returns false if there's no such ban.
+  pub fn set_timer(&mut self, channel: &str, mask: &str, length: Duration) -> bool {
+    match self.bans.find_mut(&ban_key(channel, 'b', mask)) {
+      Some(ban) => {
+        ban.expires = Some(UTC::now() + length);
+        self.backends.get(ban.backend).update(ban);
+      },
+      None => return false
+    }
+    self.save();
+    true
+  }
+  /// Kicks somebody we just banned, if we kick on ban, telling them why.
+  /// The kick waits for the ban to go out, so they can't just rejoin.
+  pub fn kick(&mut self, channel: &str, nick: &[u8], length: Duration, reason: &str) {
+    match self.kick_reason {
+      Some(ref template) => {
+        let msg = kick_message(template.as_slice(), length, reason);
+        self.kicks.push((channel.to_string(), nick.to_vec(), msg));
+      },
+      None => ()
+    }
+  }
+  /// We left a channel. Its bans wait for us to come back, like after a restart,
+  /// since we can't lift them from outside; anything else we had queued there is dropped.
+  pub fn part(&mut self, channel: &str) {
+    for (_, ban) in self.bans.mut_iter().filter(|&(_, ref ban)| ban.channel.as_slice() == channel) {
+      ban.awaiting_join = true;
+    }
+    self.backends.modes.queue.forget(channel);
+    self.kicks.retain(|&(ref c, _, _)| c.as_slice() != channel);
+    self.sent.retain(|sent| sent.channel.as_slice() != channel);
+    self.syncing.remove(&channel.to_string());
+  }
+  /// Q answered one of our requests. Returns false if it wasn't about anything we asked.
+  /// If Q wouldn't do it, we do it with channel modes instead, and tell the mods.
+  pub fn q_reply(&mut self, text: &str) -> bool {
+    let (request, why) = match self.backends.q.reply(text) {
+      Some(QDone(request)) => {
+        info!("Q did {} {} in {}", request.command, request.mask, request.channel);
+        return true;
+      },
+      Some(QFailed(request, why)) => (request, why),
+      None => return false
+    };
+    // Q only does full bans
+    let key = ban_key(request.channel.as_slice(), 'b', request.mask.as_slice());
+    let mut ban = match self.bans.contains_key(&key) {
+      // a ban Q wouldn't set: keep it, but set it ourselves
+      true if request.command != "BANDEL" => {
+        {
+          let ban = self.bans.find_mut(&key).unwrap();
+          ban.backend = ModeBackend;
+          self.backends.modes.set(ban);
+        }
+        self.warnings.push((request.channel.clone(),
+                            format!("Q wouldn't {} {} in {} ({}), so I set it myself",
+                                    request.command, request.mask, request.channel, why)));
+        self.save();
+        return true;
+      },
+      // Q wouldn't update a ban we no longer have, no harm done
+      false if request.command != "BANDEL" => return true,
+      _ => Ban::new(request.channel.as_slice(), request.mask.clone(), FullBan, Duration::zero(), "", "")
+    };
+    // a ban Q wouldn't lift: try -b instead
+    ban.backend = ModeBackend;
+    self.backends.modes.lift(&ban);
+    self.warnings.push((request.channel.clone(),
+                        format!("Q wouldn't lift {} in {} ({}), so I tried it myself",
+                                request.mask, request.channel, why)));
+    true
+  }
+  /// Whether we have ops in a channel, from NAMES and MODE.
+  /// Without them, mode changes and kicks there wait until we do.
+  pub fn set_opped(&mut self, channel: &str, opped: bool) {
+    self.backends.modes.queue.hold(channel, !opped);
+  }
+  pub fn has_ops(&self, channel: &str) -> bool {
+    !self.backends.modes.queue.is_held(channel)
+  }
+  /// Can a ban like this go in right away, through the channel's `backend`?
+  /// Services don't need us to have ops.
+  pub fn can_apply(&self, channel: &str, kind: &BanKind, backend: BackendKind) -> bool {
+    (*kind == FullBan && backend == QBackend) || self.has_ops(channel)
+  }
+  /// The server echoed back one of our MODE lines, so it went through.
+  pub fn modes_confirmed(&mut self, channel: &str) {
+    self.confirm(channel, |action| match *action { SetModes(..) => true, _ => false });
+  }
+  /// The server echoed back one of our KICKs.
+  pub fn kick_confirmed(&mut self, channel: &str) {
+    self.confirm(channel, |action| match *action { Kick(..) => true, _ => false });
+  }
+  fn confirm(&mut self, channel: &str, matches: |&Action| -> bool) {
+    match self.sent.iter().position(|sent| sent.channel.as_slice() == channel && matches(&sent.action)) {
+      Some(idx) => { self.sent.remove(idx); },
+      None => ()
+    }
+  }
+  /// The server said we need ops for something we did in a channel (482).
+  /// That's the oldest thing we sent there, so queue it up again for when we're opped,
+  /// and tell the mods.
+  pub fn not_opped(&mut self, channel: &str) {
+    self.set_opped(channel, false);
+    let sent = match self.sent.iter().position(|sent| sent.channel.as_slice() == channel) {
+      Some(idx) => self.sent.remove(idx).unwrap(),
+      None => return
+    };
+    let what = match sent.action {
+      SetModes(changes) => {
+        let what = format!("set {}", changes.iter()
+                           .map(|c| format!("{}{} {}", if c.adding { '+' } else { '-' }, c.mode,
+                                            c.param.as_ref().map_or("", |p| p.as_slice())))
+                           .collect::<Vec<String>>().as_slice().connect(", "));
+        for change in changes.move_iter() {
+          self.backends.modes.queue.push(channel, change);
+        }
+        what
+      },
+      Kick(nick, msg) => {
+        let what = format!("kick {}", String::from_utf8_lossy(nick.as_slice()));
+        self.kicks.push((channel.to_string(), nick, msg));
+        what
+      }
+    };
+    self.warnings.push((channel.to_string(),
+                        format!("Couldn't {} in {}, I'm not opped! I'll try again when I am.", what, channel)));
+  }
+  /// Sets how many mode changes go in one MODE line, from ISUPPORT.
+  pub fn set_modes_per_line(&mut self, modes: uint) {
+    self.modes_per_line = modes;
+  }
+  /// Sends requests to services, and queued mode changes several to a line,
+  /// then any kicks waiting on them. Unless `partial` is set, mode changes that
+  /// wouldn't fill a line are held back in case more come along;
+  /// returns true if any are still waiting (not counting ones waiting for ops).
+  pub fn flush_modes(&mut self, conn: &mut Conn, partial: bool) -> bool {
+    for msg in self.backends.q.take_outbox().iter() {
+      conn.privmsg(Q_ADDRESS.as_bytes(), msg.as_bytes());
+    }
+    for line in self.backends.modes.queue.take_lines(self.modes_per_line, partial).move_iter() {
+      {
+        let (changes, params) = line.to_args();
+        let mut args = vec![line.channel.as_bytes(), changes.as_bytes()];
+        for param in params.iter() {
+          args.push(param.as_bytes());
+        }
+        conn.send_command(IRCCmd("MODE".into_maybe_owned()), args.as_slice(), false);
+      }
+      self.sent.push(Sent { channel: line.channel, action: SetModes(line.changes), at: UTC::now() });
+    }
+    if self.backends.modes.queue.has_sendable() {
+      return true;
+    }
+    // kicks in channels where we're not opped wait along with the bans
+    let (held, kicks) = mem::replace(&mut self.kicks, Vec::new()).partition(|&(ref channel, _, _)| {
+      self.backends.modes.queue.is_held(channel.as_slice())
+    });
+    self.kicks = held;
+    for (channel, nick, msg) in kicks.move_iter() {
+      conn.send_command(IRCCmd("KICK".into_maybe_owned()),
+        [channel.as_bytes(), nick.as_slice(), msg.as_bytes()], true);
+      self.sent.push(Sent { channel: channel, action: Kick(nick, msg), at: UTC::now() });
+    }
+    false
   }
   pub fn set_ban_length(&mut self, length: Duration) {
     self.ban_length = length;
@@ -68,4 +794,312 @@ impl BanManager {
   pub fn get_ban_length(&self) -> Duration {
     self.ban_length
   }
+
+  fn load(&mut self) {
+    let contents = match File::open(&self.store).read_to_string() {
+      Ok(contents) => contents,
+      Err(err) => {
+        warn!("Couldn't read bans from {}, starting with none: {}", self.store.display(), err);
+        return;
+      }
+    };
+    match json::decode::<Store>(contents.as_slice()) {
+      Ok(store) => {
+        for record in store.bans.move_iter() {
+          self.track(Ban::from_record(record));
+        }
+        // strikes from before they wore off start wearing off now
+        let now = UTC::now();
+        self.strikes = store.strikes.move_iter()
+          .map(|r| ((r.channel, r.mask), Strikes {
+            count: r.strikes,
+            last: r.last.map_or(now, |secs| UTC.timestamp(secs, 0))
+          }))
+          .collect();
+        info!("Loaded {} bans from {}", self.bans.len(), self.store.display());
+      },
+      Err(err) => error!("Ban store {} is corrupt, ignoring it: {}", self.store.display(), err)
+    }
+  }
+  /// Writes all bans to the store.
+  fn save(&self) {
+    let now = UTC::now();
+    let store = Store {
+      bans: self.iter().map(|ban| ban.to_record()).collect(),
+      // worn off strikes aren't worth keeping
+      strikes: self.strikes.iter()
+        .filter(|&(_, strikes)| now - strikes.last < self.strike_reset)
+        .map(|(&(ref channel, ref mask), strikes)| StrikeRecord {
+          mask: mask.clone(),
+          channel: channel.clone(),
+          strikes: strikes.count,
+          last: Some(strikes.last.timestamp())
+        }).collect()
+    };
+    match files::replace(&self.store, json::encode(&store).as_slice()) {
+      Ok(()) => (),
+      Err(err) => error!("Couldn't save bans to {}: {}", self.store.display(), err)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{Duration, UTC};
+  use banbackend::{Backends, ModeBackend};
+  use std::collections::{HashMap, HashSet};
+  use modes::{ModeChange, ModeQueue};
+  use super::{Ban, BanManager, FullBan, Quiet, Sent, SetModes, kick_message};
+
+  fn manager(bans: Vec<Ban>) -> BanManager {
+    let mut mgr = BanManager {
+      bans: HashMap::new(),
+      ban_length: Duration::minutes(5),
+      escalation: 2.0,
+      strikes: HashMap::new(),
+      strike_reset: Duration::days(30),
+      kick_reason: None,
+      quiet_first: false,
+      syncing: HashMap::new(),
+      list_limit: None,
+      warnings: Vec::new(),
+      backends: Backends::new(),
+      modes_per_line: 3,
+      kicks: Vec::new(),
+      sent: Vec::new(),
+      next_id: 1,
+      store: Path::new("/nonexistent/bans.json")
+    };
+    for ban in bans.move_iter() {
+      mgr.track(ban);
+    }
+    mgr
+  }
+
+  fn ban(mask: &str, length: Duration) -> Ban {
+    Ban::new("#test", mask.to_string(), FullBan, length, "testing", "tester")
+  }
+
+  fn masks<'a, I: Iterator<&'a Ban>>(bans: I) -> Vec<String> {
+    bans.map(|b| b.mask.clone()).collect()
+  }
+
+  /// Only expired bans come out, even when they're not at the front
+  #[test]
+  fn expired_in_the_middle() {
+    let mut mgr = manager(vec![
+      ban("*!*@a", Duration::minutes(5)),
+      ban("*!*@b", Duration::minutes(-5)),
+      ban("*!*@c", Duration::minutes(5)),
+      ban("*!*@d", Duration::minutes(-1)),
+    ]);
+
+    let expired = mgr.take_expired(UTC::now());
+    assert_eq!(masks(expired.iter()), vec!["*!*@b".to_string(), "*!*@d".to_string()]);
+    assert_eq!(masks(mgr.iter()), vec!["*!*@a".to_string(), "*!*@c".to_string()]);
+  }
+
+  /// Removing several bans at once mustn't skip or panic
+  #[test]
+  fn all_expired() {
+    let mut mgr = manager(vec![
+      ban("*!*@a", Duration::minutes(-3)),
+      ban("*!*@b", Duration::minutes(-2)),
+      ban("*!*@c", Duration::minutes(-1)),
+    ]);
+
+    assert_eq!(mgr.take_expired(UTC::now()).len(), 3);
+    assert!(mgr.bans.is_empty());
+  }
+
+  #[test]
+  fn nothing_expired() {
+    let mut mgr = manager(vec![ban("*!*@a", Duration::minutes(5))]);
+
+    assert!(mgr.take_expired(UTC::now()).is_empty());
+    assert_eq!(mgr.bans.len(), 1);
+  }
+
+  #[test]
+  fn kick_template() {
+    assert_eq!(kick_message("Temp-banned for {length}: {reason}", Duration::minutes(5), "This isn't Twitch chat.").as_slice(),
+               "Temp-banned for 5m: This isn't Twitch chat.");
+  }
+
+  /// Bans loaded from disk wait until we're back in the channel
+  #[test]
+  fn awaiting_join_kept() {
+    let mut loaded = ban("*!*@a", Duration::minutes(-5));
+    loaded.awaiting_join = true;
+    let mut mgr = manager(vec![loaded]);
+
+    assert!(mgr.take_expired(UTC::now()).is_empty());
+    assert_eq!(mgr.bans.len(), 1);
+  }
+
+  /// A 482 puts what we sent back in the queue, to wait for ops
+  #[test]
+  fn not_opped_requeues() {
+    let mut mgr = manager(Vec::new());
+    mgr.sent.push(Sent {
+      channel: "#test".to_string(),
+      action: SetModes(vec![ModeChange { adding: true, mode: 'b', param: Some("*!*@a".to_string()) }]),
+      at: UTC::now()
+    });
+
+    mgr.not_opped("#test");
+    assert!(!mgr.has_ops("#test"));
+    assert!(mgr.sent.is_empty());
+    assert_eq!(mgr.backends.modes.queue.held_count("#test"), 1);
+    assert_eq!(mgr.take_warnings().len(), 1);
+  }
+
+  /// Parting keeps the channel's bans for when we're back, but nothing else
+  #[test]
+  fn parted() {
+    let mut mgr = manager(vec![ban("*!*@a", Duration::minutes(-5))]);
+    mgr.kick_reason = Some("{reason}".to_string());
+    mgr.set_opped("#test", false);
+    mgr.apply(ban("*!*@b", Duration::minutes(5)), ModeBackend);
+    mgr.kick("#test", b"fred", Duration::minutes(5), "testing");
+
+    mgr.part("#test");
+    assert!(mgr.take_expired(UTC::now()).is_empty());
+    assert_eq!(mgr.bans.len(), 2);
+    assert!(mgr.bans.values().all(|ban| ban.awaiting_join));
+    assert_eq!(mgr.backends.modes.queue.held_count("#test"), 0);
+    assert!(mgr.has_ops("#test"));
+    assert!(mgr.kicks.is_empty());
+  }
+
+  /// Only new bans are strikes; making an existing one longer isn't
+  #[test]
+  fn extending_isnt_a_strike() {
+    let mut mgr = manager(Vec::new());
+    mgr.apply(ban("*!*@a", Duration::minutes(5)), ModeBackend);
+    mgr.apply(ban("*!*@a", Duration::minutes(10)), ModeBackend);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 1);
+    assert_eq!(mgr.bans.len(), 1);
+    assert_eq!(mgr.next_ban_length("#test", "*!*@a"), Duration::minutes(10));
+
+    // once it's lifted, banning them again is another strike
+    mgr.lift_mask("#test", "*!*@a", ModeBackend);
+    mgr.apply(ban("*!*@a", Duration::minutes(10)), ModeBackend);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 2);
+  }
+
+  /// A mask that's stayed out of trouble long enough starts over
+  #[test]
+  fn strikes_wear_off() {
+    let mut mgr = manager(Vec::new());
+    mgr.apply(ban("*!*@a", Duration::minutes(5)), ModeBackend);
+    mgr.lift_mask("#test", "*!*@a", ModeBackend);
+    mgr.apply(ban("*!*@a", Duration::minutes(5)), ModeBackend);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 2);
+
+    mgr.strikes.find_mut(&("#test".to_string(), "*!*@a".to_string())).unwrap().last = UTC::now() - Duration::days(31);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 0);
+    assert_eq!(mgr.next_ban_length("#test", "*!*@a"), Duration::minutes(5));
+    mgr.lift_mask("#test", "*!*@a", ModeBackend);
+    mgr.apply(ban("*!*@a", Duration::minutes(5)), ModeBackend);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 1);
+  }
+
+  /// A ban and a quiet on the same mask are kept apart
+  #[test]
+  fn keyed_by_list_entry() {
+    let mut mgr = manager(vec![
+      ban("*!*@a", Duration::minutes(5)),
+      Ban::new("#test", "*!*@a".to_string(), Quiet('q', "*!*@a".to_string()), Duration::minutes(5), "testing", "tester"),
+      Ban::new("#other", "*!*@a".to_string(), FullBan, Duration::minutes(5), "testing", "tester"),
+    ]);
+    assert_eq!(mgr.bans.len(), 3);
+    assert!(!mgr.ban_added("#test", "*!*@a", "someone"));
+
+    assert!(mgr.ban_removed("#test", 'q', "*!*@a").is_some());
+    assert!(mgr.ban_removed("#test", 'q', "*!*@a").is_none());
+    assert_eq!(masks(mgr.iter()), vec!["*!*@a".to_string(), "*!*@a".to_string()]);
+    assert!(mgr.iter().all(|ban| *ban.kind() == FullBan));
+  }
+
+  /// A nearly full list makes room by lifting whichever of our bans was going to end first
+  #[test]
+  fn make_room_lifts_soonest() {
+    let mut human = ban("*!*@human", Duration::minutes(1));
+    human.expires = None;
+    let mut mgr = manager(vec![
+      ban("*!*@later", Duration::minutes(30)),
+      human,
+      ban("*!*@sooner", Duration::minutes(10)),
+    ]);
+    mgr.set_list_limit(Some(("bq".to_string(), 5)));
+
+    mgr.apply(ban("*!*@new", Duration::minutes(5)), ModeBackend);
+    assert_eq!(masks(mgr.iter()), vec!["*!*@later".to_string(), "*!*@human".to_string(), "*!*@new".to_string()]);
+    assert_eq!(mgr.take_warnings().len(), 1);
+    // -b for the one lifted, +b for the new one
+    assert_eq!(mgr.backends.modes.queue.held_count("#test"), 2);
+  }
+
+  /// Humans' bans are never lifted to make room
+  #[test]
+  fn make_room_spares_humans() {
+    let mut human = ban("*!*@human", Duration::minutes(1));
+    human.expires = None;
+    let mut mgr = manager(vec![human]);
+    mgr.set_list_limit(Some(("b".to_string(), 3)));
+
+    mgr.apply(ban("*!*@new", Duration::minutes(5)), ModeBackend);
+    assert_eq!(mgr.bans.len(), 2);
+    let warnings = mgr.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    let (_, ref warning) = *warnings.get(0);
+    assert!(warning.as_slice().contains("none of those bans are mine"));
+  }
+
+  /// A ban the server wouldn't take (478) is forgotten, and isn't a strike
+  #[test]
+  fn list_full_forgets() {
+    let mut mgr = manager(Vec::new());
+    mgr.apply(ban("*!*@a", Duration::minutes(5)), ModeBackend);
+    mgr.lift_mask("#test", "*!*@a", ModeBackend);
+    mgr.apply(ban("*!*@a", Duration::minutes(10)), ModeBackend);
+    mgr.apply(Ban::new("#other", "*!*@a".to_string(), FullBan, Duration::minutes(5), "testing", "tester"), ModeBackend);
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 2);
+
+    mgr.list_full("#test", "*!*@a");
+    assert_eq!(masks(mgr.iter()), vec!["*!*@a".to_string()]);
+    assert_eq!(mgr.iter().next().unwrap().channel(), "#other");
+    assert_eq!(mgr.strikes("#test", "*!*@a"), 1);
+    assert_eq!(mgr.strikes("#other", "*!*@a"), 1);
+    assert_eq!(mgr.take_warnings().len(), 1);
+  }
+
+  /// A ban set while the list is coming in isn't on it, but it's not gone either
+  #[test]
+  fn sync_keeps_bans_in_flight() {
+    let mut mgr = manager(Vec::new());
+    mgr.syncing.insert("#test".to_string(), HashSet::new());
+    mgr.sync_ban("#test", "*!*@human", "someone");
+    mgr.apply(ban("*!*@queued", Duration::minutes(5)), ModeBackend);
+    mgr.apply(ban("*!*@sent", Duration::minutes(5)), ModeBackend);
+    // one's gone out but hasn't come back yet, the other's still queued
+    mgr.backends.modes.queue = ModeQueue::new();
+    mgr.backends.modes.queue.push("#test", ModeChange { adding: true, mode: 'b', param: Some("*!*@queued".to_string()) });
+    mgr.sent.push(Sent {
+      channel: "#test".to_string(),
+      action: SetModes(vec![ModeChange { adding: true, mode: 'b', param: Some("*!*@sent".to_string()) }]),
+      at: UTC::now()
+    });
+    mgr.apply(ban("*!*@lifted", Duration::minutes(5)), ModeBackend);
+    mgr.backends.modes.queue = ModeQueue::new();
+
+    mgr.end_sync("#test");
+    assert_eq!(masks(mgr.iter()), vec!["*!*@human".to_string(), "*!*@queued".to_string(), "*!*@sent".to_string()]);
+
+    // the +b comes back, and the timer's still there
+    assert!(!mgr.ban_added("#test", "*!*@queued", "NoFunBot"));
+    assert!(!mgr.ban_added("#test", "*!*@sent", "NoFunBot"));
+    assert!(mgr.iter().filter(|ban| ban.mask() != "*!*@human").all(|ban| ban.expires().is_some()));
+  }
 }
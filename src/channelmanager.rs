@@ -1,51 +1,219 @@
-use std::collections::{HashMap, HashSet};
-use irc::conn::Conn;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::File;
+use chrono::{DateTime, Duration, UTC};
+use serialize::json;
+use irc::conn::{Conn, IRCCmd};
+use banbackend::{BackendKind, ModeBackend, QBackend, Q_ADDRESS};
+use files;
+use history::{History, Message};
+use masks::{MaskStrategy, HostMask, SmartMask};
 
-#[deriving(Clone, PartialEq)]
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
 pub enum ChannelType {
   Moderate, // we mod this channel
     Control // we are controlled here
 }
 
+/// Who we can ask to let us into a channel, or to op us there.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub enum Services {
+  /// QuakeNet's Q
+  QServices,
+  /// Atheme and Anope's ChanServ
+  ChanServ
+}
+
+/// What we ask services for.
+pub enum ServiceRequest {
+  Invite,
+  Unban,
+  Op
+}
+
+impl Services {
+  /// Reads what a mod called them, e.g. "Q" or "chanserv".
+  pub fn from_name(name: &str) -> Option<Services> {
+    match name.to_ascii_lower().as_slice() {
+      "q" => Some(QServices),
+      "chanserv" => Some(ChanServ),
+      _ => None
+    }
+  }
+  /// What they're called, for telling the mods.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      QServices => "Q",
+      ChanServ => "ChanServ"
+    }
+  }
+  /// Who to message, and what to tell them.
+  pub fn request(&self, request: ServiceRequest, channel: &str) -> (&'static str, String) {
+    match *self {
+      QServices => (Q_ADDRESS, format!("{} {}", match request {
+        Invite => "INVITE",
+        Unban => "UNBANME",
+        Op => "OP"
+      }, channel)),
+      ChanServ => ("ChanServ", format!("{} {}", match request {
+        Invite => "INVITE",
+        Unban => "UNBAN",
+        Op => "OP"
+      }, channel))
+    }
+  }
+}
+
+/// Staying in a channel this long after joining resets the rejoin backoff,
+/// so only a kick loop gets the long waits.
+static STAY_RESET_MINS: i64 = 10;
+/// How soon to retry a join once we've asked services to let us in.
+static SERVICES_RETRY_SECS: i64 = 5;
+/// Failed joins are retried after this long, doubling each time...
+static JOIN_RETRY_SECS: i64 = 30;
+/// ...up to this long.
+static MAX_JOIN_RETRY_SECS: i64 = 30 * 60;
+
+/// How long to wait after the nth try at joining, before trying again.
+fn retry_wait(attempts: uint) -> Duration {
+  Duration::seconds(cmp::min(JOIN_RETRY_SECS << cmp::min(attempts, 16), MAX_JOIN_RETRY_SECS))
+}
+
 #[deriving(Clone)]
 pub struct IRCChannel {
   name: String,
   chantype: ChannelType,
 
-  nicks: HashSet<String>,
+  /// Nicks in the channel, and their status modes, e.g. "ov" for an op with voice.
+  members: HashMap<String, String>,
   joined: bool,
+  /// Whether we have ops here, from NAMES and MODE.
+  opped: bool,
+  /// +k, if the channel has one.
+  key: Option<String>,
+  /// Joins tried since we last stayed in here a while. See `STAY_RESET_MINS`.
+  join_attempts: uint,
+  /// When we last got in.
+  joined_at: Option<DateTime<UTC>>,
+  /// When to try joining again, if we're not in.
+  next_join: Option<DateTime<UTC>>,
+  /// Whether the pending retry is a quick one after asking services,
+  /// so a refusal from them goes back to the usual backoff.
+  retrying_soon: bool,
+  /// Who to ask for invites, unbans and ops here. None means nobody.
+  services: Option<Services>,
   
-  stopword: Option<String>
+  stopword: Option<String>,
+  /// What's been said here lately.
+  recent: History,
+  /// For moderated channels: the control channels that get our reports about it,
+  /// and whose members can moderate it. Empty means all of them.
+  control_channels: Vec<String>,
+  /// Members with this status, or better, aren't moderated. None means nobody's exempt.
+  exempt_status: Option<char>,
+  mask_strategy: MaskStrategy,
+  ban_backend: BackendKind
+}
+
+/// How a channel looks on disk.
+/// The Options are None in lists saved before those settings were kept.
+#[deriving(Encodable, Decodable)]
+struct ChannelRecord {
+  name: String,
+  chantype: ChannelType,
+  control_channels: Option<Vec<String>>,
+  key: Option<String>,
+  services: Option<Services>,
+  mask_strategy: Option<MaskStrategy>,
+  ban_backend: Option<BackendKind>
 }
 
 /// keeps track of all the channels we're in,
 /// as well as nicks in them.
 pub struct ChannelManager {
-  channels: HashMap<String, IRCChannel>
+  channels: HashMap<String, IRCChannel>,
+  /// Set once we're logged in and have started joining channels.
+  joining: bool,
+  /// Where the channel list is kept, so channels joined at runtime stick around.
+  store: Path,
+  /// How many messages each channel's `recent` holds.
+  history_len: uint
 }
 
 impl ChannelManager {
-  pub fn new_blank() -> ChannelManager {
-    ChannelManager { channels: HashMap::new() }
+  pub fn new_blank(store: Path, history_len: uint) -> ChannelManager {
+    ChannelManager { channels: HashMap::new(), joining: false, store: store, history_len: history_len }
   }
-  pub fn new() -> ChannelManager {
-    let mut mgr = ChannelManager::new_blank();
-    mgr.add_channel("#r/globaloffensive", Moderate);
-    mgr.add_channel("#gobotmods", Control);
+  /// Creates a channel manager with the saved channel list,
+  /// or the built-in one if nothing's been saved yet.
+  pub fn new(store: Path, history_len: uint) -> ChannelManager {
+    let mut mgr = ChannelManager::new_blank(store, history_len);
+    if !mgr.load() {
+      mgr.add_channel("#r/globaloffensive", Moderate);
+      mgr.add_channel("#gobotmods", Control);
+      mgr.find_mut("#r/globaloffensive").map(|ch| {
+        ch.set_mask_strategy(SmartMask);
+        ch.set_services(Some(QServices));
+        ch.set_control_channels(vec!["#gobotmods".to_string()]);
+        ch.set_ban_backend(QBackend);
+      });
+    }
     mgr
   }
 
   /// Adds a channel to the list.
   pub fn add_channel(&mut self, name: &str, chantype: ChannelType) {
-    self.channels.insert(name.to_string(), IRCChannel::new(name, chantype));
+    self.channels.insert(name.to_string(), IRCChannel::new(name, chantype, self.history_len));
+  }
+
+  /// Forgets a channel and leaves it. Returns false if we didn't know it.
+  pub fn remove_channel(&mut self, conn: &mut Conn, name: &str) -> bool {
+    match self.channels.pop(&name.to_string()) {
+      Some(chan) => {
+        if chan.joined {
+          conn.send_command(IRCCmd("PART".into_maybe_owned()), [name.as_bytes()], false);
+        }
+        true
+      },
+      None => false
+    }
+  }
+
+  /// Somebody changed nick: they keep their status wherever they are.
+  pub fn handle_nick(&mut self, old: &str, new: &str) {
+    for (_, chan) in self.channels.mut_iter() {
+      chan.handle_nick(old, new);
+    }
   }
 
-  /// Joins any channels we are not already in.
+  /// Somebody (NOT us) quit, leaving every channel at once.
+  pub fn handle_quit(&mut self, nick: &str) {
+    for (_, chan) in self.channels.mut_iter() {
+      chan.members.remove(&nick.to_string());
+    }
+  }
+
+  /// One line per channel `shown` picks, by name. See `IRCChannel::describe`.
+  pub fn describe_channels(&self, shown: |&str| -> bool) -> Vec<String> {
+    let mut names: Vec<&String> = self.channels.keys().filter(|name| shown(name.as_slice())).collect();
+    names.sort();
+    names.iter().map(|&name| self.channels.find(name).unwrap().describe()).collect()
+  }
+
+  /// Joins any channels we are not already in, unless we tried lately.
   /// Note this does not mark the channels as joined,
   /// as we need confirmation from the server.
-  pub fn join_channels(&self, conn: &mut Conn) {
-    for (name, _) in self.channels.iter().filter(|&(_, c)| !c.joined) {
-      conn.join(name.as_slice().as_bytes(), [])
+  pub fn join_channels(&mut self, conn: &mut Conn) {
+    self.joining = true;
+    let now = UTC::now();
+    for (_, chan) in self.channels.mut_iter().filter(|&(_, ref c)| !c.joined && c.next_join.map_or(true, |t| t <= now)) {
+      chan.join(conn);
+    }
+  }
+  /// Retries joins that are due. Does nothing until we're logged in.
+  pub fn retry_joins(&mut self, conn: &mut Conn) {
+    if self.joining {
+      self.join_channels(conn);
     }
   }
   pub fn find<'a>(&'a self, name: &str) -> Option<&'a IRCChannel> {
@@ -59,10 +227,109 @@ impl ChannelManager {
   }
   /// Prints a raw slice of bytes to all control channels.
   pub fn log_to_control_channels_bytes(&self, conn: &mut Conn, msg: &[u8]) {
-    for (name, _) in self.channels.iter().filter(|&(_, s)| s.chantype == Control) {
-      conn.privmsg(name.as_slice().as_bytes(), msg);
+    for name in self.control_channel_names().iter() {
+      conn.privmsg(name.as_bytes(), msg);
+    }
+  }
+  /// Tells the mods about something that happened in a channel,
+  /// in just the control channels that oversee it.
+  pub fn log_for(&self, conn: &mut Conn, channel: &str, msg: &str) {
+    self.log_for_bytes(conn, channel, msg.as_bytes());
+  }
+  pub fn log_for_bytes(&self, conn: &mut Conn, channel: &str, msg: &[u8]) {
+    for name in self.control_channels_for(channel).iter() {
+      conn.privmsg(name.as_bytes(), msg);
     }
   }
+  /// Tells the mods about something that touches several channels,
+  /// once in each control channel that oversees any of them.
+  pub fn log_for_channels(&self, conn: &mut Conn, channels: &[String], msg: &str) {
+    let mut names: Vec<String> = channels.iter()
+      .flat_map(|channel| self.control_channels_for(channel.as_slice()).move_iter())
+      .collect();
+    names.sort();
+    names.dedup();
+    for name in names.iter() {
+      conn.privmsg(name.as_bytes(), msg.as_bytes());
+    }
+  }
+  /// All our control channels.
+  pub fn control_channel_names(&self) -> Vec<String> {
+    self.channels.iter()
+      .filter(|&(_, s)| s.chantype == Control)
+      .map(|(name, _)| name.clone())
+      .collect()
+  }
+  /// The control channels that oversee a channel: the ones it names, or all of them
+  /// if it names none (or we don't know it). A control channel oversees itself.
+  pub fn control_channels_for(&self, channel: &str) -> Vec<String> {
+    match self.find(channel) {
+      Some(chan) if chan.chantype == Control => vec![channel.to_string()],
+      Some(chan) if !chan.control_channels.is_empty() => chan.control_channels.clone(),
+      _ => self.control_channel_names()
+    }
+  }
+  /// Moderated channels that name a control channel as one of theirs, by name.
+  pub fn reporting_to(&self, control: &str) -> Vec<String> {
+    let mut names: Vec<String> = self.channels.values()
+      .filter(|chan| chan.control_channels.iter().any(|name| name.as_slice() == control))
+      .map(|chan| chan.name.clone())
+      .collect();
+    names.sort();
+    names
+  }
+  /// Reads the channel list. Returns false if there isn't one yet.
+  fn load(&mut self) -> bool {
+    let contents = match File::open(&self.store).read_to_string() {
+      Ok(contents) => contents,
+      Err(_) => return false
+    };
+    match json::decode::<Vec<ChannelRecord>>(contents.as_slice()) {
+      Ok(records) => {
+        for record in records.move_iter() {
+          self.add_channel(record.name.as_slice(), record.chantype);
+          let chan = self.find_mut(record.name.as_slice()).unwrap();
+          match record.control_channels {
+            Some(controls) => chan.set_control_channels(controls),
+            None => ()
+          }
+          chan.set_key(record.key);
+          chan.set_services(record.services);
+          match record.mask_strategy {
+            Some(strategy) => chan.set_mask_strategy(strategy),
+            None => ()
+          }
+          match record.ban_backend {
+            Some(backend) => chan.set_ban_backend(backend),
+            None => ()
+          }
+        }
+        info!("Loaded {} channels from {}", self.channels.len(), self.store.display());
+        true
+      },
+      Err(err) => {
+        error!("Channel list {} is corrupt, ignoring it: {}", self.store.display(), err);
+        false
+      }
+    }
+  }
+  /// Writes the channel list.
+  pub fn save(&self) {
+    let records: Vec<ChannelRecord> = self.channels.iter().map(|(_, chan)| ChannelRecord {
+      name: chan.name.clone(),
+      chantype: chan.chantype.clone(),
+      control_channels: Some(chan.control_channels.clone()),
+      key: chan.key.clone(),
+      services: chan.services,
+      mask_strategy: Some(chan.mask_strategy),
+      ban_backend: Some(chan.ban_backend)
+    }).collect();
+    match files::replace(&self.store, json::encode(&records).as_slice()) {
+      Ok(()) => (),
+      Err(err) => error!("Couldn't save channels to {}: {}", self.store.display(), err)
+    }
+  }
+
   /// Is a given nick in any control channels? (etc. a mod)
   pub fn nick_is_mod(&self, nick: &str) -> bool {
     for (_, chan) in self.channels.iter().filter(|&(_, s)| s.chantype == Control) {
@@ -72,39 +339,181 @@ impl ChannelManager {
     }
     false
   }
+  /// Is a nick a mod for this channel, i.e. in a control channel that oversees it?
+  pub fn nick_is_mod_for(&self, nick: &str, channel: &str) -> bool {
+    self.control_channels_for(channel).iter()
+      .any(|name| self.find(name.as_slice()).map_or(false, |chan| chan.contains_nick(nick)))
+  }
 }
 
 impl IRCChannel {
-  fn new(name: &str, chantype: ChannelType) -> IRCChannel {
+  fn new(name: &str, chantype: ChannelType, history_len: uint) -> IRCChannel {
     IRCChannel {
       name: name.to_string(),
       chantype: chantype,
-      nicks: HashSet::new(),
+      members: HashMap::new(),
       joined: false,
-      stopword: None
+      opped: false,
+      key: None,
+      join_attempts: 0,
+      joined_at: None,
+      next_join: None,
+      retrying_soon: false,
+      services: None,
+      stopword: None,
+      recent: History::new(history_len),
+      control_channels: Vec::new(),
+      exempt_status: Some('v'),
+      mask_strategy: HostMask,
+      ban_backend: ModeBackend
+    }
+  }
+
+  /// Sends a JOIN, with the key if we know it, and schedules a retry
+  /// in case it doesn't work.
+  fn join(&mut self, conn: &mut Conn) {
+    match self.key {
+      Some(ref key) => conn.join(self.name.as_bytes(), [key.as_bytes()]),
+      None => conn.join(self.name.as_bytes(), [])
     }
+    self.schedule_retry();
+  }
+  /// Backs off a bit more before the next join.
+  fn schedule_retry(&mut self) {
+    self.next_join = Some(UTC::now() + retry_wait(self.join_attempts));
+    self.join_attempts += 1;
   }
 
   /// We have successfully joined a channel! Hooray!
+  /// The backoff isn't reset until we've stayed a while, in case we're kicked straight out.
   pub fn join_ok(&mut self) {
-    self.joined = true
+    self.joined = true;
+    self.joined_at = Some(UTC::now());
+    self.next_join = None;
+    self.retrying_soon = false;
+  }
+
+  /// We got kicked. Forget who's here, and rejoin after the usual backoff,
+  /// so we don't fight a kick loop.
+  pub fn kicked(&mut self) {
+    let stayed = self.joined_at.map_or(false, |t| UTC::now() - t >= Duration::minutes(STAY_RESET_MINS));
+    if stayed {
+      self.join_attempts = 0;
+    }
+    self.joined = false;
+    self.opped = false;
+    self.members.clear();
+    self.schedule_retry();
+  }
+
+  /// A join failed and services might let us in, so try again soon rather
+  /// than waiting out the backoff. Only once per backoff step, so services
+  /// that keep refusing don't get asked every few seconds.
+  /// Returns false if we should wait after all.
+  pub fn retry_soon(&mut self) -> bool {
+    if self.retrying_soon {
+      self.retrying_soon = false;
+      return false;
+    }
+    self.retrying_soon = true;
+    self.next_join = Some(UTC::now() + Duration::seconds(SERVICES_RETRY_SECS));
+    true
+  }
+
+  /// How long until we try joining again, if we're not in.
+  pub fn next_join_in(&self) -> Option<Duration> {
+    self.next_join.map(|t| t - UTC::now())
   }
 
   /// Handles somebody else (NOT us) joining a channel.
   pub fn handle_join(&mut self, nick: &str) {
-    self.nicks.insert(nick.to_string());
+    self.members.insert(nick.to_string(), String::new());
+  }
+
+  /// Handles a NAMES entry, with the status modes from its prefixes.
+  pub fn handle_names(&mut self, nick: &str, status: &[char]) {
+    self.members.insert(nick.to_string(), String::from_chars(status));
   }
 
   /// Handles somebody else (NOT us) leaving a channel.
   pub fn handle_part(&mut self, nick: &str) {
-    match self.nicks.remove(&nick.to_string()) {
-      true => (),
-      false => error!("{} parted from {}, but was never in nicks!", self.name, nick)
+    match self.members.pop(&nick.to_string()) {
+      Some(_) => (),
+      None => error!("{} parted from {}, but was never in nicks!", self.name, nick)
+    }
+  }
+
+  /// Handles somebody here changing nick. Does nothing if they're not here.
+  pub fn handle_nick(&mut self, old: &str, new: &str) {
+    match self.members.pop(&old.to_string()) {
+      Some(status) => { self.members.insert(new.to_string(), status); },
+      None => ()
+    }
+  }
+
+  /// Handles a MODE change to somebody's status, like +o or -v.
+  pub fn handle_status(&mut self, nick: &str, mode: char, adding: bool) {
+    match self.members.find_mut(&nick.to_string()) {
+      Some(status) => {
+        let kept: String = status.as_slice().chars().filter(|&m| m != mode).collect();
+        *status = kept;
+        if adding {
+          status.push_char(mode);
+        }
+      },
+      None => warn!("{} got {}{} in {}, but isn't in nicks!", nick, if adding { '+' } else { '-' }, mode, self.name)
     }
   }
 
+  /// Somebody's status modes here, e.g. "ov". None if they're not here.
+  pub fn status<'a>(&'a self, nick: &str) -> Option<&'a str> {
+    self.members.find_equiv(&nick).map(|status| status.as_slice())
+  }
+
+  pub fn is_opped(&self) -> bool {
+    self.opped
+  }
+  pub fn set_opped(&mut self, opped: bool) {
+    self.opped = opped;
+  }
+
+  pub fn is_joined(&self) -> bool {
+    self.joined
+  }
+
+  /// One line summary for mods, e.g.
+  /// "#chan (Moderate, joined, 42 members): stopword "kappa", SmartMask, QBackend, exempt +v and up"
+  pub fn describe(&self) -> String {
+    let mut rules = vec![format!("{}", self.mask_strategy), format!("{}", self.ban_backend)];
+    if !self.control_channels.is_empty() {
+      rules.push(format!("reports to {}", self.control_channels.as_slice().connect(" ")));
+    }
+    match self.services {
+      Some(services) => rules.push(format!("asks {} for help", services.name())),
+      None => ()
+    }
+    match self.stopword {
+      Some(ref word) => rules.insert(0, format!("stopword \"{}\"", word)),
+      None => ()
+    }
+    rules.push(match self.exempt_status {
+      Some(mode) => format!("exempt +{} and up", mode),
+      None => "nobody exempt".to_string()
+    });
+    format!("{} ({}, {}, {} members): {}",
+            self.name,
+            self.chantype,
+            if self.joined { "joined" } else { "not joined" },
+            self.members.len(),
+            rules.as_slice().connect(", "))
+  }
+
+  pub fn is_control(&self) -> bool {
+    self.chantype == Control
+  }
+
   pub fn contains_nick(&self, nick: &str) -> bool {
-    self.nicks.contains_equiv(&nick)
+    self.members.find_equiv(&nick).is_some()
   }
 
   pub fn get_stopword<'a>(&'a self) -> Option<&'a str> {
@@ -116,17 +525,64 @@ impl IRCChannel {
   pub fn set_stopword<'a>(&mut self, stopword: Option<String>) {
     self.stopword = stopword;
   }
+
+  pub fn set_key(&mut self, key: Option<String>) {
+    self.key = key;
+  }
+
+  pub fn get_services(&self) -> Option<Services> {
+    self.services
+  }
+  pub fn set_services(&mut self, services: Option<Services>) {
+    self.services = services;
+  }
+
+  pub fn set_control_channels(&mut self, channels: Vec<String>) {
+    self.control_channels = channels;
+  }
+
+  pub fn remember(&mut self, message: Message) {
+    self.recent.push(message);
+  }
+  pub fn recent<'a>(&'a self) -> &'a History {
+    &self.recent
+  }
+
+  /// Members with this status or better aren't moderated, see `ISupport::outranks`.
+  pub fn get_exempt_status(&self) -> Option<char> {
+    self.exempt_status
+  }
+
+  /// How we build ban masks for this channel.
+  pub fn get_mask_strategy(&self) -> MaskStrategy {
+    self.mask_strategy
+  }
+  pub fn set_mask_strategy(&mut self, strategy: MaskStrategy) {
+    self.mask_strategy = strategy;
+  }
+
+  /// Who sets and lifts our bans in this channel.
+  pub fn get_ban_backend(&self) -> BackendKind {
+    self.ban_backend
+  }
+  pub fn set_ban_backend(&mut self, backend: BackendKind) {
+    self.ban_backend = backend;
+  }
 }
 
 #[cfg(test)]
 mod test {
-  use super::{IRCChannel, Moderate};
+  use chrono::{Duration, UTC};
+  use std::io::{File, TempDir};
+  use banbackend::QBackend;
+  use masks::SmartMask;
+  use super::{ChannelManager, IRCChannel, Moderate, Control, ChanServ, retry_wait};
 
   #[test]
   fn nick_tracking() {
     let test_nick = "fredbloggs";
 
-    let mut chan = IRCChannel::new("#test", Moderate);
+    let mut chan = IRCChannel::new("#test", Moderate, 30);
     assert!(!chan.contains_nick(test_nick));
 
     chan.handle_join(test_nick);
@@ -141,7 +597,7 @@ mod test {
   fn duplicate_nicks() {
     let test_nick = "fredbloggs";
 
-    let mut chan = IRCChannel::new("#test", Moderate);
+    let mut chan = IRCChannel::new("#test", Moderate, 30);
     
     for _ in range(0u, 10) {
       chan.handle_join(test_nick);
@@ -151,4 +607,150 @@ mod test {
     chan.handle_part(test_nick);
     assert!(!chan.contains_nick(test_nick));
   }
+
+  #[test]
+  fn status_tracking() {
+    let mut chan = IRCChannel::new("#test", Moderate, 30);
+    chan.handle_names("fredbloggs", ['o', 'v']);
+    assert_eq!(chan.status("fredbloggs"), Some("ov"));
+
+    chan.handle_status("fredbloggs", 'o', false);
+    assert_eq!(chan.status("fredbloggs"), Some("v"));
+
+    // +v twice doesn't stack either
+    chan.handle_status("fredbloggs", 'v', true);
+    assert_eq!(chan.status("fredbloggs"), Some("v"));
+
+    chan.handle_part("fredbloggs");
+    assert_eq!(chan.status("fredbloggs"), None);
+  }
+
+  /// Nick changes and quits reach every channel somebody's in
+  #[test]
+  fn nick_and_quit() {
+    let mut mgr = ChannelManager::new_blank(Path::new("/nonexistent/channels.json"), 30);
+    mgr.add_channel("#a", Moderate);
+    mgr.add_channel("#b", Control);
+    mgr.find_mut("#a").unwrap().handle_names("fred", ['o']);
+    mgr.find_mut("#b").unwrap().handle_join("fred");
+    mgr.find_mut("#b").unwrap().handle_join("bob");
+
+    mgr.handle_nick("fred", "freddy");
+    assert_eq!(mgr.find("#a").unwrap().status("freddy"), Some("o"));
+    assert!(!mgr.find("#a").unwrap().contains_nick("fred"));
+    assert!(mgr.find("#b").unwrap().contains_nick("freddy"));
+    assert!(mgr.nick_is_mod_for("freddy", "#a"));
+    assert!(!mgr.nick_is_mod_for("fred", "#a"));
+
+    mgr.handle_quit("freddy");
+    assert!(!mgr.find("#a").unwrap().contains_nick("freddy"));
+    assert!(!mgr.find("#b").unwrap().contains_nick("freddy"));
+    assert!(mgr.find("#b").unwrap().contains_nick("bob"));
+  }
+
+  /// Each failed join waits twice as long, up to a limit
+  #[test]
+  fn join_backoff() {
+    assert_eq!(retry_wait(0), Duration::seconds(30));
+    assert_eq!(retry_wait(2), Duration::minutes(2));
+    assert_eq!(retry_wait(100), Duration::minutes(30));
+  }
+
+  /// Kicks back off like failed joins, until we manage to stay in a while
+  #[test]
+  fn join_state() {
+    let mut chan = IRCChannel::new("#test", Moderate, 30);
+    chan.join_attempts = 3;
+    chan.next_join = Some(UTC::now());
+    chan.join_ok();
+    assert!(chan.next_join.is_none());
+
+    // kicked straight back out
+    chan.kicked();
+    assert!(!chan.is_joined());
+    assert!(chan.next_join_in().unwrap() > retry_wait(2));
+    assert_eq!(chan.join_attempts, 4);
+
+    chan.join_ok();
+    chan.joined_at = Some(UTC::now() - Duration::minutes(super::STAY_RESET_MINS));
+    chan.kicked();
+    assert_eq!(chan.join_attempts, 1);
+    assert!(chan.next_join_in().unwrap() <= retry_wait(0));
+  }
+
+  /// Services that keep refusing get one quick retry per backoff step, not one every tick
+  #[test]
+  fn services_retries() {
+    let quick = Duration::seconds(super::SERVICES_RETRY_SECS);
+    let mut chan = IRCChannel::new("#test", Moderate, 30);
+    for step in range(0u, 4) {
+      chan.schedule_retry();
+      assert!(chan.retry_soon());
+      assert!(chan.next_join_in().unwrap() <= quick);
+
+      // services said no: back to the backoff
+      chan.schedule_retry();
+      assert!(!chan.retry_soon());
+      assert!(chan.next_join_in().unwrap() > retry_wait(step * 2) - Duration::seconds(1));
+    }
+    chan.join_ok();
+    chan.schedule_retry();
+    assert!(chan.retry_soon());
+  }
+
+  /// Each community's mods only hear about, and moderate, their own channel
+  #[test]
+  fn control_channel_mapping() {
+    let mut mgr = ChannelManager::new_blank(Path::new("/nonexistent/channels.json"), 30);
+    mgr.add_channel("#a", Moderate);
+    mgr.add_channel("#b", Moderate);
+    mgr.add_channel("#amods", Control);
+    mgr.add_channel("#bmods", Control);
+    mgr.find_mut("#a").unwrap().set_control_channels(vec!["#amods".to_string()]);
+    mgr.find_mut("#amods").unwrap().handle_join("alice");
+
+    assert_eq!(mgr.control_channels_for("#a"), vec!["#amods".to_string()]);
+    assert_eq!(mgr.control_channels_for("#amods"), vec!["#amods".to_string()]);
+    assert_eq!(mgr.control_channels_for("#b").len(), 2);
+    assert!(mgr.nick_is_mod_for("alice", "#a"));
+    assert_eq!(mgr.reporting_to("#amods"), vec!["#a".to_string()]);
+    assert!(mgr.reporting_to("#bmods").is_empty());
+    // #b doesn't say, so any mod will do
+    assert!(mgr.nick_is_mod_for("alice", "#b"));
+    mgr.find_mut("#b").unwrap().set_control_channels(vec!["#bmods".to_string()]);
+    assert!(!mgr.nick_is_mod_for("alice", "#b"));
+  }
+
+  /// Lists saved before control channels were per-channel still load
+  #[test]
+  fn old_channel_list() {
+    let dir = TempDir::new("nofunbot").unwrap();
+    let path = dir.path().join("channels.json");
+    File::create(&path).write_str(r#"[{"name":"#a","chantype":"Moderate"},{"name":"#amods","chantype":"Control"}]"#).unwrap();
+
+    let mut mgr = ChannelManager::new_blank(path.clone(), 30);
+    assert!(mgr.load());
+    assert!(mgr.find("#a").is_some());
+    assert!(mgr.find("#amods").unwrap().is_control());
+    assert_eq!(mgr.control_channels_for("#a"), vec!["#amods".to_string()]);
+
+    // and come back the same after saving in the new shape
+    {
+      let chan = mgr.find_mut("#a").unwrap();
+      chan.set_control_channels(vec!["#amods".to_string()]);
+      chan.set_key(Some("sekrit".to_string()));
+      chan.set_services(Some(ChanServ));
+      chan.set_mask_strategy(SmartMask);
+      chan.set_ban_backend(QBackend);
+    }
+    mgr.save();
+    let mut reloaded = ChannelManager::new_blank(path, 30);
+    assert!(reloaded.load());
+    let chan = reloaded.find("#a").unwrap();
+    assert_eq!(chan.control_channels, vec!["#amods".to_string()]);
+    assert_eq!(chan.key, Some("sekrit".to_string()));
+    assert_eq!(chan.get_services(), Some(ChanServ));
+    assert_eq!(chan.mask_strategy, SmartMask);
+    assert_eq!(chan.get_ban_backend(), QBackend);
+  }
 }
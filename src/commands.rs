@@ -0,0 +1,890 @@
+use chrono::{Duration, UTC};
+use irc;
+use irc::conn::Conn;
+
+use banmanager::{Ban, FullBan};
+use channelmanager::{IRCChannel, Services, Moderate, Control};
+use duration;
+use history::{Message, CONTEXT_LINES};
+use permissions::{Role, Helper, Mod, Admin};
+use ticket::Ticket;
+use NoFunBot;
+
+/// What an argument is expected to look like.
+#[deriving(Clone, PartialEq)]
+pub enum ArgKind {
+  NickArg,
+  /// A ban mask, or anything else that isn't a channel
+  MaskArg,
+  DurationArg,
+  ChannelArg,
+  NumberArg,
+  /// Eats the rest of the line.
+  TextArg
+}
+
+pub struct ArgSpec {
+  pub name: &'static str,
+  pub kind: ArgKind,
+  /// Optional args are only taken if the next word looks right.
+  pub optional: bool
+}
+
+/// A parsed argument.
+#[deriving(Clone)]
+pub enum Arg {
+  Nick(String),
+  Mask(String),
+  Length(Duration),
+  Channel(String),
+  Number(uint),
+  Text(String)
+}
+
+/// Parsed arguments, in the same order as the command's `ArgSpec`s.
+/// Missing optional arguments are `None`.
+pub struct Args {
+  values: Vec<Option<Arg>>
+}
+
+/// Who ran a command, and where.
+pub struct Context<'a> {
+  pub src: &'a str,
+  /// Who they really are; roles go by this, not the nick.
+  pub user: &'a irc::User,
+  /// None if the command came by private message.
+  pub channel: Option<&'a str>
+}
+
+impl<'a> Context<'a> {
+  /// Replies go back where the command came from.
+  pub fn reply(&self, conn: &mut Conn, msg: &str) {
+    conn.privmsg(self.channel.unwrap_or(self.src).as_bytes(), msg.as_bytes());
+  }
+
+  /// The channel a command acts on: the #channel argument if given,
+  /// otherwise the channel it was said in.
+  pub fn target_channel<'b>(&'b self, explicit: Option<&'b str>) -> Result<&'b str, String> {
+    match explicit.or(self.channel) {
+      Some(channel) => Ok(channel),
+      None => Err("Which channel? Give me a #channel.".to_string())
+    }
+  }
+}
+
+/// Err holds a message for whoever ran the command.
+pub type CommandResult = Result<(), String>;
+pub type Handler = fn(&mut NoFunBot, &mut Conn, &Context, &Args) -> CommandResult;
+
+pub struct Command {
+  pub name: &'static str,
+  pub aliases: &'static [&'static str],
+  pub args: &'static [ArgSpec],
+  pub help: &'static str,
+  pub role: Role,
+  pub handler: Handler
+}
+
+pub static COMMANDS: &'static [Command] = &[
+  Command {
+    name: "help",
+    aliases: &["commands"],
+    args: &[ArgSpec { name: "command", kind: TextArg, optional: true }],
+    help: "Lists commands, or explains one.",
+    role: Helper,
+    handler: help
+  },
+  Command {
+    name: "stopword",
+    aliases: &[],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true },
+            ArgSpec { name: "word", kind: TextArg, optional: false }],
+    help: "Bans the next person to say a word.",
+    role: Helper,
+    handler: stopword
+  },
+  Command {
+    name: "clear_stopword",
+    aliases: &["clearstopword"],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true }],
+    help: "Clears the stopword.",
+    role: Helper,
+    handler: clear_stopword
+  },
+  Command {
+    name: "forgive",
+    aliases: &[],
+    args: &[ArgSpec { name: "nick", kind: NickArg, optional: false }],
+    help: "Resets a user's infractions.",
+    role: Mod,
+    handler: forgive
+  },
+  Command {
+    name: "ban_length",
+    aliases: &["banlength"],
+    args: &[ArgSpec { name: "duration", kind: DurationArg, optional: false }],
+    help: "Sets how long automatic bans last.",
+    role: Admin,
+    handler: ban_length
+  },
+  Command {
+    name: "ban",
+    aliases: &["kb"],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true },
+            ArgSpec { name: "nick|mask", kind: MaskArg, optional: false },
+            ArgSpec { name: "duration", kind: DurationArg, optional: true },
+            ArgSpec { name: "reason", kind: TextArg, optional: true }],
+    help: "Bans somebody by nick (if they've talked lately) or mask.",
+    role: Mod,
+    handler: ban
+  },
+  Command {
+    name: "unban",
+    aliases: &[],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true },
+            ArgSpec { name: "mask|id", kind: MaskArg, optional: false }],
+    help: "Lifts a ban, by mask or by the id from \"bans\".",
+    role: Mod,
+    handler: unban
+  },
+  Command {
+    name: "bans",
+    aliases: &["banlist"],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true }],
+    help: "Lists active bans, with time left and reason.",
+    role: Helper,
+    handler: bans
+  },
+  Command {
+    name: "context",
+    aliases: &[],
+    args: &[ArgSpec { name: "nick", kind: NickArg, optional: false }],
+    help: "Shows what somebody said lately, and what was said just before.",
+    role: Helper,
+    handler: context
+  },
+  Command {
+    name: "extend",
+    aliases: &[],
+    args: &[ArgSpec { name: "id", kind: NumberArg, optional: false },
+            ArgSpec { name: "duration", kind: DurationArg, optional: false }],
+    help: "Makes a ban last longer.",
+    role: Mod,
+    handler: extend
+  },
+  Command {
+    name: "join",
+    aliases: &[],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: false },
+            ArgSpec { name: "moderate|control [#control ...] [key=...] [services=Q|ChanServ]", kind: TextArg, optional: true }],
+    help: "Joins a channel, to moderate it (the default) or take commands there. \
+           A moderated channel can name the control channels that oversee it. \
+           Give the key if it has one, and who to ask for invites, unbans and ops.",
+    role: Admin,
+    handler: join
+  },
+  Command {
+    name: "part",
+    aliases: &["leave"],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: false }],
+    help: "Leaves a channel for good.",
+    role: Admin,
+    handler: part
+  },
+  Command {
+    name: "channels",
+    aliases: &[],
+    args: &[],
+    help: "Lists the channels you look after, and what I'm doing in them.",
+    role: Helper,
+    handler: channels
+  },
+  Command {
+    name: "stats",
+    aliases: &[],
+    args: &[],
+    help: "Shows how many users, bans and tickets I'm keeping track of.",
+    role: Helper,
+    handler: stats
+  },
+  Command {
+    name: "timer",
+    aliases: &[],
+    args: &[ArgSpec { name: "#channel", kind: ChannelArg, optional: true },
+            ArgSpec { name: "mask", kind: MaskArg, optional: false },
+            ArgSpec { name: "duration", kind: DurationArg, optional: false }],
+    help: "Makes an existing ban, e.g. one set by hand, expire after a while.",
+    role: Mod,
+    handler: timer
+  },
+  Command {
+    name: "tickets",
+    aliases: &[],
+    args: &[],
+    help: "Lists open tickets.",
+    role: Helper,
+    handler: tickets
+  },
+  Command {
+    name: "approve",
+    aliases: &[],
+    args: &[ArgSpec { name: "id", kind: NumberArg, optional: false },
+            ArgSpec { name: "duration", kind: DurationArg, optional: true }],
+    help: "Bans the user a ticket is about.",
+    role: Mod,
+    handler: approve
+  },
+  Command {
+    name: "warn",
+    aliases: &[],
+    args: &[ArgSpec { name: "id", kind: NumberArg, optional: false }],
+    help: "Counts a ticket as an infraction and warns the user.",
+    role: Helper,
+    handler: warn
+  },
+  Command {
+    name: "dismiss",
+    aliases: &[],
+    args: &[ArgSpec { name: "id", kind: NumberArg, optional: false }],
+    help: "Closes a ticket without doing anything.",
+    role: Helper,
+    handler: dismiss
+  },
+];
+
+/// Looks a command up by name or alias.
+pub fn find(name: &str) -> Option<&'static Command> {
+  COMMANDS.iter().find(|c| c.name == name || c.aliases.iter().any(|&a| a == name))
+}
+
+impl Command {
+  /// e.g. "ban_length <duration>"
+  pub fn usage(&self) -> String {
+    let mut usage = self.name.to_string();
+    for spec in self.args.iter() {
+      usage.push_str(if spec.optional {
+        format!(" [{}]", spec.name)
+      } else {
+        format!(" <{}>", spec.name)
+      }.as_slice());
+    }
+    usage
+  }
+
+  /// Checks words against the command's ArgSpecs.
+  /// Err holds a message saying what was wrong.
+  pub fn parse_args(&self, words: &[&str]) -> Result<Args, String> {
+    let mut values = Vec::new();
+    let mut words = words.iter().map(|&w| w).filter(|w| !w.is_empty()).peekable();
+
+    for spec in self.args.iter() {
+      if spec.kind == TextArg {
+        let text = words.by_ref().collect::<Vec<&str>>().as_slice().connect(" ");
+        if text.is_empty() && !spec.optional {
+          return Err(format!("Missing {}.", spec.name));
+        }
+        values.push(if text.is_empty() { None } else { Some(Text(text)) });
+        continue;
+      }
+
+      let parsed = words.peek().and_then(|&word| parse_arg(spec.kind, word));
+      match parsed {
+        Some(arg) => {
+          words.next();
+          values.push(Some(arg));
+        },
+        None if spec.optional => values.push(None),
+        None => return Err(match words.peek() {
+          Some(word) => format!("\"{}\" isn't a valid {}: {}.", word, spec.name, describe(spec.kind)),
+          None => format!("Missing {}.", spec.name)
+        })
+      }
+    }
+
+    match words.next() {
+      Some(word) => Err(format!("Unexpected \"{}\".", word)),
+      None => Ok(Args { values: values })
+    }
+  }
+}
+
+fn parse_arg(kind: ArgKind, word: &str) -> Option<Arg> {
+  match kind {
+    NickArg if !word.starts_with("#") => Some(Nick(word.to_string())),
+    MaskArg if !word.starts_with("#") => Some(Mask(word.to_string())),
+    DurationArg => duration::parse(word).map(|d| Length(d)),
+    ChannelArg if word.starts_with("#") => Some(Channel(word.to_string())),
+    NumberArg => from_str(word).map(|n| Number(n)),
+    _ => None
+  }
+}
+
+fn describe(kind: ArgKind) -> &'static str {
+  match kind {
+    NickArg => "expected a nick",
+    MaskArg => "expected a mask like *!*@host",
+    DurationArg => "expected a duration like 90m, 2h or 1d",
+    ChannelArg => "expected a #channel",
+    NumberArg => "expected a number",
+    TextArg => "expected some text"
+  }
+}
+
+impl Args {
+  pub fn nick<'a>(&'a self, idx: uint) -> Option<&'a str> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Nick(ref nick))) => Some(nick.as_slice()),
+      _ => None
+    }
+  }
+  pub fn mask<'a>(&'a self, idx: uint) -> Option<&'a str> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Mask(ref mask))) => Some(mask.as_slice()),
+      _ => None
+    }
+  }
+  pub fn duration(&self, idx: uint) -> Option<Duration> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Length(d))) => Some(d),
+      _ => None
+    }
+  }
+  pub fn channel<'a>(&'a self, idx: uint) -> Option<&'a str> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Channel(ref chan))) => Some(chan.as_slice()),
+      _ => None
+    }
+  }
+  pub fn number(&self, idx: uint) -> Option<uint> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Number(n))) => Some(n),
+      _ => None
+    }
+  }
+  pub fn text<'a>(&'a self, idx: uint) -> Option<&'a str> {
+    match self.values.as_slice().get(idx) {
+      Some(&Some(Text(ref text))) => Some(text.as_slice()),
+      _ => None
+    }
+  }
+}
+
+/// An argument the command's spec says isn't optional. `parse_args` already made sure
+/// it's there, so this only fails if a handler and its spec disagree.
+fn required<T>(arg: Option<T>, name: &str) -> Result<T, String> {
+  match arg {
+    Some(arg) => Ok(arg),
+    None => Err(format!("Missing {}.", name))
+  }
+}
+
+/// Can whoever ran a command act on a channel? Admins can act anywhere,
+/// everybody else only where their control channel oversees.
+fn oversees(bot: &NoFunBot, ctx: &Context, channel: &str) -> bool {
+  bot.perms.role_of(ctx.user) == Admin || bot.chanmgr.nick_is_mod_for(ctx.src, channel)
+}
+
+fn check_oversight(bot: &NoFunBot, ctx: &Context, channel: &str) -> CommandResult {
+  if oversees(bot, ctx, channel) {
+    Ok(())
+  } else {
+    Err(format!("You're not a mod for {}.", channel))
+  }
+}
+
+/// Looks up a channel a command wants to act on.
+fn find_channel<'a>(bot: &'a mut NoFunBot, ctx: &Context, channel: &str) -> Result<&'a mut IRCChannel, String> {
+  try!(check_oversight(bot, ctx, channel));
+  match bot.chanmgr.find_mut(channel) {
+    Some(chan) => Ok(chan),
+    None => Err(format!("I'm not in {}.", channel))
+  }
+}
+
+fn help(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  match args.text(0) {
+    Some(name) => {
+      let command = match find(name) {
+        Some(command) => command,
+        None => return Err(format!("No such command \"{}\".", name))
+      };
+      let aliases = if command.aliases.is_empty() {
+        String::new()
+      } else {
+        format!(" (also: {})", command.aliases.connect(", "))
+      };
+      conn.privmsg(ctx.src.as_bytes(), format!("{} - {}{} Needs {}.",
+                                               command.usage(),
+                                               command.help,
+                                               aliases,
+                                               command.role).as_bytes());
+    },
+    None => {
+      let role = bot.perms.role_of(ctx.user);
+      let names: Vec<&str> = COMMANDS.iter()
+        .filter(|c| c.role <= role)
+        .map(|c| c.name)
+        .collect();
+      conn.privmsg(ctx.src.as_bytes(), format!("Commands: {}. Try \"help <command>\".",
+                                               names.as_slice().connect(", ")).as_bytes());
+    }
+  }
+  Ok(())
+}
+
+fn stopword(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(ctx.target_channel(args.channel(0)));
+  let word = try!(required(args.text(1), "word")).to_string();
+
+  try!(find_channel(bot, ctx, channel)).set_stopword(Some(word.clone()));
+
+  ctx.reply(conn, format!("Okay, {}, next person to say {} in {} gets kickbanned!",
+                          ctx.src,
+                          word,
+                          channel).as_slice());
+  Ok(())
+}
+
+fn clear_stopword(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(ctx.target_channel(args.channel(0)));
+  try!(find_channel(bot, ctx, channel)).set_stopword(None);
+  ctx.reply(conn, format!("Stopword cleared in {}.", channel).as_slice());
+  Ok(())
+}
+
+fn forgive(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let target_nick = try!(required(args.nick(0), "nick"));
+  // infractions count everywhere, so forgiving takes a mod for every channel they're from
+  let channels = bot.usermgr.find(target_nick).map_or(Vec::new(), |state| state.infraction_channels());
+  for channel in channels.iter() {
+    try!(check_oversight(bot, ctx, channel.as_slice()));
+  }
+  if !bot.usermgr.forgive(target_nick) {
+    return Err(format!("I haven't seen {} talk lately, so I don't know who they are.", target_nick));
+  }
+  info!("Forgiving {} by {}'s request...", target_nick, ctx.src);
+  let msg = format!("{} forgave {}...", ctx.src, target_nick);
+  if channels.is_empty() {
+    ctx.reply(conn, msg.as_slice());
+  } else {
+    bot.chanmgr.log_for_channels(conn, channels.as_slice(), msg.as_slice());
+  }
+  Ok(())
+}
+
+fn ban_length(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let len = try!(required(args.duration(0), "duration"));
+  bot.banmgr.set_ban_length(len);
+  bot.chanmgr.log_to_control_channels(conn, format!("{} set ban length to {}",
+                                                    ctx.src,
+                                                    duration::format(len)).as_slice());
+  Ok(())
+}
+
+/// Does this look like a ban mask (or extban) rather than a nick?
+fn is_mask(target: &str) -> bool {
+  target.contains_char('!') || target.contains_char('@') || target.contains_char(':')
+}
+
+fn ban(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(ctx.target_channel(args.channel(0)));
+  try!(find_channel(bot, ctx, channel));
+  let target = try!(required(args.mask(1), "mask"));
+  let len = args.duration(2).unwrap_or(bot.banmgr.get_ban_length());
+  if len <= Duration::zero() {
+    return Err("A ban has to last longer than that.".to_string());
+  }
+  let reason = args.text(3).map_or(format!("Banned by {}", ctx.src), |r| r.to_string());
+
+  if is_mask(target) {
+    let backend = bot.ban_backend(channel);
+    bot.banmgr.apply(Ban::new(channel, target.to_string(), FullBan, len, reason.as_slice(), ctx.src), backend);
+  } else {
+    let user = match bot.usermgr.find(target).and_then(|state| state.last_seen_as.clone()) {
+      Some(user) => user,
+      None => return Err(format!("I haven't seen {} talk, so I don't know their host. Try a mask.", target))
+    };
+    bot.ban_user(conn, channel, &user, Some(len), reason.as_slice(), ctx.src);
+  }
+
+  bot.chanmgr.log_for(conn, channel, format!("{} {} from {} for {}, as {} asked: {}",
+                                             bot.ban_verb(channel, &FullBan), target, channel,
+                                             duration::format(len), ctx.src, reason).as_slice());
+  Ok(())
+}
+
+/// The channel a ban is in, if whoever ran the command can touch it.
+fn ban_channel(bot: &NoFunBot, ctx: &Context, id: uint) -> Result<String, String> {
+  let channel = match bot.banmgr.iter().find(|ban| ban.id() == id) {
+    Some(ban) => ban.channel().to_string(),
+    None => return Err(format!("There's no ban #{}.", id))
+  };
+  try!(check_oversight(bot, ctx, channel.as_slice()));
+  Ok(channel)
+}
+
+fn unban(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let target = try!(required(args.mask(1), "mask"));
+
+  let (mask, channel) = match from_str::<uint>(target) {
+    Some(id) => {
+      let ban_chan = try!(ban_channel(bot, ctx, id));
+      match args.channel(0) {
+        Some(channel) if channel != ban_chan.as_slice() => {
+          return Err(format!("Ban #{} is in {}, not {}.", id, ban_chan, channel));
+        },
+        _ => ()
+      }
+      match bot.banmgr.lift(id) {
+        Some(ban) => (ban.mask().to_string(), ban.channel().to_string()),
+        None => return Err(format!("There's no ban #{}.", id))
+      }
+    },
+    None => {
+      let channel = try!(ctx.target_channel(args.channel(0)));
+      try!(check_oversight(bot, ctx, channel));
+      let backend = bot.ban_backend(channel);
+      if bot.banmgr.lift_mask(channel, target, backend).is_none() {
+        ctx.reply(conn, format!("I didn't set a ban on {} in {}, but I'll try to lift it.", target, channel).as_slice());
+      }
+      (target.to_string(), channel.to_string())
+    }
+  };
+
+  bot.chanmgr.log_for(conn, channel.as_slice(), format!("{} lifted the ban on {} in {}", ctx.src, mask, channel).as_slice());
+  Ok(())
+}
+
+fn bans(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = args.channel(0);
+  let bans: Vec<String> = bot.banmgr.iter()
+    .filter(|ban| ban.is_active() && channel.map_or(true, |c| c == ban.channel()))
+    .filter(|ban| oversees(bot, ctx, ban.channel()))
+    .map(|ban| ban.describe())
+    .collect();
+
+  if bans.is_empty() {
+    conn.privmsg(ctx.src.as_bytes(), b"No active bans.");
+    return Ok(());
+  }
+  for ban in bans.iter().take(MAX_LISTED) {
+    conn.privmsg(ctx.src.as_bytes(), ban.as_bytes());
+  }
+  if bans.len() > MAX_LISTED {
+    conn.privmsg(ctx.src.as_bytes(), format!("...and {} more.", bans.len() - MAX_LISTED).as_bytes());
+  }
+  Ok(())
+}
+
+fn context(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let nick = try!(required(args.nick(0), "nick"));
+  let said: Vec<Message> = match bot.usermgr.find(nick) {
+    Some(state) => state.recent.last(MAX_LISTED).move_iter()
+      .filter(|message| oversees(bot, ctx, message.channel.as_slice()))
+      .collect(),
+    None => Vec::new()
+  };
+  let last = match said.last() {
+    Some(message) => message.clone(),
+    None => return Err(format!("I haven't seen {} say anything lately.", nick))
+  };
+
+  // what the channel was talking about when they last spoke
+  let before = bot.chanmgr.find(last.channel.as_slice())
+    .map_or(Vec::new(), |chan| chan.recent().leading_up_to(&last, CONTEXT_LINES));
+  if !before.is_empty() {
+    conn.privmsg(ctx.src.as_bytes(), format!("Before {} last spoke in {}:", nick, last.channel).as_bytes());
+    for message in before.iter() {
+      conn.privmsg(ctx.src.as_bytes(), format!("  {}", message.describe()).as_bytes());
+    }
+  }
+  conn.privmsg(ctx.src.as_bytes(), format!("What {} said lately:", nick).as_bytes());
+  for message in said.iter() {
+    conn.privmsg(ctx.src.as_bytes(), format!("  {}", message.describe()).as_bytes());
+  }
+  Ok(())
+}
+
+fn extend(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let id = try!(required(args.number(0), "id"));
+  let by = try!(required(args.duration(1), "duration"));
+  let channel = try!(ban_channel(bot, ctx, id));
+
+  let expires = match bot.banmgr.extend(id, by) {
+    Some(expires) => expires,
+    None => return Err(format!("There's no ban #{}.", id))
+  };
+  bot.chanmgr.log_for(conn, channel.as_slice(), format!("{} extended ban #{} by {}, {} left now",
+                                                        ctx.src, id, duration::format(by),
+                                                        duration::format(expires - UTC::now())).as_slice());
+  Ok(())
+}
+
+fn join(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(required(args.channel(0), "#channel"));
+  let words: Vec<&str> = args.text(1).map_or(Vec::new(), |text| text.words().collect());
+  let (chantype, options) = match words.as_slice() {
+    ["moderate", ..options] => (Moderate, options),
+    ["control", ..options] => (Control, options),
+    options => (Moderate, options)
+  };
+  let mut controls = Vec::new();
+  let mut key = None;
+  let mut services = None;
+  for &word in options.iter() {
+    if word.starts_with("#") {
+      if chantype == Control {
+        return Err("Only moderated channels report to control channels.".to_string());
+      }
+      controls.push(word.to_string());
+    } else if word.starts_with("key=") {
+      key = Some(word.slice_from("key=".len()).to_string());
+    } else if word.starts_with("services=") {
+      let name = word.slice_from("services=".len());
+      services = match Services::from_name(name) {
+        Some(services) => Some(services),
+        None => return Err(format!("I don't know any services called \"{}\". Try Q or ChanServ.", name))
+      };
+    } else {
+      return Err(format!("\"{}\" isn't moderate, control, a #control channel, key=... or services=....", word));
+    }
+  }
+  if bot.chanmgr.find(channel).is_some() {
+    return Err(format!("I'm already in {}.", channel));
+  }
+
+  bot.chanmgr.add_channel(channel, chantype);
+  bot.chanmgr.find_mut(channel).map(|chan| {
+    chan.set_control_channels(controls);
+    chan.set_key(key);
+    chan.set_services(services);
+  });
+  bot.chanmgr.save();
+  bot.chanmgr.join_channels(conn);
+  bot.chanmgr.log_for(conn, channel, format!("{} asked me to join {} ({})", ctx.src, channel, chantype).as_slice());
+  Ok(())
+}
+
+fn part(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(required(args.channel(0), "#channel"));
+  // their reports would go to every control channel instead
+  let reporting = bot.chanmgr.reporting_to(channel);
+  if !reporting.is_empty() {
+    return Err(format!("{} still report to {}. Part them, or join them again with other control channels, first.",
+                       reporting.as_slice().connect(", "), channel));
+  }
+  if !bot.chanmgr.remove_channel(conn, channel) {
+    return Err(format!("I'm not in {}.", channel));
+  }
+  bot.chanmgr.save();
+  bot.banmgr.part(channel);
+  let closed = bot.tickets.close_channel(channel).len();
+  // it's gone, so everybody hears
+  let mut msg = format!("{} asked me to leave {}", ctx.src, channel);
+  if closed > 0 {
+    msg.push_str(format!(", closing {} open ticket{}", closed, if closed == 1 {""} else {"s"}).as_slice());
+  }
+  bot.chanmgr.log_to_control_channels(conn, msg.as_slice());
+  // the control channels heard, but somebody asking privately didn't
+  if ctx.channel.is_none() {
+    ctx.reply(conn, format!("Left {}.", channel).as_slice());
+  }
+  Ok(())
+}
+
+fn channels(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, _: &Args) -> CommandResult {
+  let lines = bot.chanmgr.describe_channels(|channel| oversees(bot, ctx, channel));
+  if lines.is_empty() {
+    conn.privmsg(ctx.src.as_bytes(), b"None of my channels are yours.");
+  }
+  for line in lines.iter() {
+    conn.privmsg(ctx.src.as_bytes(), line.as_bytes());
+  }
+  Ok(())
+}
+
+fn stats(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, _: &Args) -> CommandResult {
+  // only what's in channels they oversee; users aren't tied to a channel
+  let bans = bot.banmgr.iter().filter(|ban| ban.is_active() && oversees(bot, ctx, ban.channel())).count();
+  let tickets = bot.tickets.iter().filter(|ticket| oversees(bot, ctx, ticket.channel())).count();
+  ctx.reply(conn, format!("Tracking {} users (cap {}), {} with infractions. {} active bans, {} open tickets.",
+                          bot.usermgr.len(),
+                          bot.usermgr.max_users(),
+                          bot.usermgr.with_infractions(),
+                          bans,
+                          tickets).as_slice());
+  Ok(())
+}
+
+fn timer(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let channel = try!(ctx.target_channel(args.channel(0)));
+  let mask = try!(required(args.mask(1), "mask"));
+  let len = try!(required(args.duration(2), "duration"));
+  try!(check_oversight(bot, ctx, channel));
+
+  if !bot.banmgr.set_timer(channel, mask, len) {
+    return Err(format!("{} isn't banned in {}.", mask, channel));
+  }
+  bot.chanmgr.log_for(conn, channel, format!("{} set the ban on {} in {} to expire in {}",
+                                             ctx.src, mask, channel, duration::format(len)).as_slice());
+  Ok(())
+}
+
+/// Most tickets or bans we'll list at once, so we don't flood the mod.
+static MAX_LISTED: uint = 10;
+
+fn tickets(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, _: &Args) -> CommandResult {
+  let tickets: Vec<String> = bot.tickets.iter()
+    .filter(|ticket| oversees(bot, ctx, ticket.channel()))
+    .map(|ticket| ticket.describe())
+    .collect();
+
+  if tickets.is_empty() {
+    conn.privmsg(ctx.src.as_bytes(), b"No open tickets.");
+    return Ok(());
+  }
+  for ticket in tickets.iter().take(MAX_LISTED) {
+    conn.privmsg(ctx.src.as_bytes(), ticket.as_bytes());
+  }
+  if tickets.len() > MAX_LISTED {
+    conn.privmsg(ctx.src.as_bytes(), format!("...and {} more.", tickets.len() - MAX_LISTED).as_bytes());
+  }
+  Ok(())
+}
+
+fn take_ticket(bot: &mut NoFunBot, ctx: &Context, id: uint) -> Result<Ticket, String> {
+  let channel = bot.tickets.iter().find(|ticket| ticket.id() == id).map(|ticket| ticket.channel().to_string());
+  match channel {
+    Some(channel) => try!(check_oversight(bot, ctx, channel.as_slice())),
+    None => ()
+  }
+  match bot.tickets.take(id) {
+    Some(ticket) => Ok(ticket),
+    None => Err(format!("There's no open ticket #{}.", id))
+  }
+}
+
+fn approve(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let ticket = try!(take_ticket(bot, ctx, try!(required(args.number(0), "id"))));
+
+  // no length given means the usual one, escalated for repeat offenders
+  let (kind, len) = bot.ban_user(conn, ticket.channel(), ticket.user(), args.duration(1), ticket.info_msg(), ctx.src);
+
+  bot.chanmgr.log_for(conn, ticket.channel(), format!("{} approved ticket #{}. {} {} in {} for {}",
+                                                      ctx.src,
+                                                      ticket.id(),
+                                                      bot.ban_verb(ticket.channel(), &kind),
+                                                      ticket.nick(),
+                                                      ticket.channel(),
+                                                      duration::format(len)).as_slice());
+  bot.log_context(conn, ticket.channel(), ticket.context());
+  Ok(())
+}
+
+fn warn(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let ticket = try!(take_ticket(bot, ctx, try!(required(args.number(0), "id"))));
+
+  bot.chanmgr.log_for(conn, ticket.channel(), format!("{} turned ticket #{} into a warning for {}",
+                                                      ctx.src,
+                                                      ticket.id(),
+                                                      ticket.nick()).as_slice());
+  if bot.infraction(conn, ticket.user(), ticket.channel(), ticket.info_msg()) {
+    bot.log_context(conn, ticket.channel(), ticket.context());
+  }
+  Ok(())
+}
+
+fn dismiss(bot: &mut NoFunBot, conn: &mut Conn, ctx: &Context, args: &Args) -> CommandResult {
+  let ticket = try!(take_ticket(bot, ctx, try!(required(args.number(0), "id"))));
+
+  bot.chanmgr.log_for(conn, ticket.channel(), format!("{} dismissed ticket #{} ({})",
+                                                      ctx.src,
+                                                      ticket.id(),
+                                                      ticket.nick()).as_slice());
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::Duration;
+  use super::{Args, find, is_mask};
+
+  fn parse(line: &str) -> Result<Args, String> {
+    let words: Vec<&str> = line.split(' ').collect();
+    find(*words.get(0)).unwrap().parse_args(words.slice_from(1))
+  }
+
+  #[test]
+  fn ban_args() {
+    let args = parse("ban #test fred 1h spamming links").unwrap();
+    assert_eq!(args.channel(0), Some("#test"));
+    assert_eq!(args.mask(1), Some("fred"));
+    assert_eq!(args.duration(2), Some(Duration::hours(1)));
+    assert_eq!(args.text(3), Some("spamming links"));
+
+    // everything but the target is optional
+    let args = parse("kb *!*@example.com").unwrap();
+    assert_eq!(args.channel(0), None);
+    assert_eq!(args.mask(1), Some("*!*@example.com"));
+    assert_eq!(args.duration(2), None);
+    assert_eq!(args.text(3), None);
+
+    // a reason that doesn't start with a duration
+    let args = parse("ban fred being rude").unwrap();
+    assert_eq!(args.duration(2), None);
+    assert_eq!(args.text(3), Some("being rude"));
+
+    assert!(parse("ban").is_err());
+    assert!(parse("ban #test").is_err());
+  }
+
+  /// A length too long to be real is the reason, not a crash
+  #[test]
+  fn ban_huge_length() {
+    let args = parse("ban fred 99999999999999w").unwrap();
+    assert_eq!(args.duration(2), None);
+    assert_eq!(args.text(3), Some("99999999999999w"));
+  }
+
+  #[test]
+  fn unban_args() {
+    let args = parse("unban #test *!*@example.com").unwrap();
+    assert_eq!(args.channel(0), Some("#test"));
+    assert_eq!(args.mask(1), Some("*!*@example.com"));
+
+    // ids come through as masks, the handler tells them apart
+    assert_eq!(parse("unban 12").unwrap().mask(1), Some("12"));
+
+    assert!(parse("unban").is_err());
+    assert!(parse("unban #test").is_err());
+    assert!(parse("unban a b").is_err());
+  }
+
+  #[test]
+  fn bans_args() {
+    assert_eq!(parse("bans").unwrap().channel(0), None);
+    assert_eq!(parse("banlist #test").unwrap().channel(0), Some("#test"));
+    assert!(parse("bans fred").is_err());
+  }
+
+  #[test]
+  fn extend_args() {
+    let args = parse("extend 3 2d").unwrap();
+    assert_eq!(args.number(0), Some(3));
+    assert_eq!(args.duration(1), Some(Duration::days(2)));
+
+    assert!(parse("extend 3").is_err());
+    assert!(parse("extend three 2d").is_err());
+    assert!(parse("extend -3 2d").is_err());
+    assert!(parse("extend 3 fish").is_err());
+    assert!(parse("extend 3 99999999999999w").is_err());
+    assert!(parse("extend 3 2d more").is_err());
+  }
+
+  #[test]
+  fn masks() {
+    assert!(is_mask("*!*@example.com"));
+    assert!(is_mask("$a:fred"));
+    assert!(!is_mask("fred"));
+  }
+}
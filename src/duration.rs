@@ -0,0 +1,120 @@
+use chrono::Duration;
+use std::num::{CheckedAdd, CheckedMul};
+
+/// Longest duration `parse` takes, a hundred years. Anything longer is
+/// somebody leaning on the keyboard, and wouldn't fit in a date anyway.
+static MAX_SECS: i64 = 100 * 365 * 86400;
+
+/// Parses a duration like `90m`, `2h`, `1d` or `1h30m`.
+/// A bare number means minutes, which is what the bot has always taken.
+/// None if it's malformed, or longer than `MAX_SECS`.
+pub fn parse(s: &str) -> Option<Duration> {
+  if s.is_empty() {
+    return None;
+  }
+  if s.chars().all(|c| c.is_digit()) {
+    return from_str(s).and_then(|n: i64| seconds(n, 60));
+  }
+
+  let mut total = 0i64;
+  let mut rest = s;
+  while !rest.is_empty() {
+    let split = rest.find(|c: char| !c.is_digit()).unwrap_or(rest.len());
+    let n: i64 = match from_str(rest.slice_to(split)) {
+      Some(n) => n,
+      None => return None
+    };
+    let unit = match rest.slice_from(split).chars().next() {
+      Some('s') => 1,
+      Some('m') => 60,
+      Some('h') => 3600,
+      Some('d') => 86400,
+      Some('w') => 7 * 86400,
+      _ => return None
+    };
+    total = match n.checked_mul(&unit).and_then(|secs| total.checked_add(&secs)) {
+      Some(total) if total <= MAX_SECS => total,
+      _ => return None
+    };
+    rest = rest.slice_from(split + 1);
+  }
+  Some(Duration::seconds(total))
+}
+
+/// `n` units of `unit` seconds, if that's not too long.
+fn seconds(n: i64, unit: i64) -> Option<Duration> {
+  match n.checked_mul(&unit) {
+    Some(secs) if secs <= MAX_SECS => Some(Duration::seconds(secs)),
+    _ => None
+  }
+}
+
+/// Formats a duration the way `parse` reads it, e.g. "1h30m".
+pub fn format(d: Duration) -> String {
+  let mut secs = d.num_seconds();
+  if secs <= 0 {
+    return "0s".to_string();
+  }
+  let mut out = String::new();
+  for &(unit, len) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)].iter() {
+    if secs >= len {
+      out.push_str(format!("{}{}", secs / len, unit).as_slice());
+      secs %= len;
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::Duration;
+  use super::{parse, format};
+
+  #[test]
+  fn units() {
+    assert_eq!(parse("90m"), Some(Duration::minutes(90)));
+    assert_eq!(parse("2h"), Some(Duration::hours(2)));
+    assert_eq!(parse("1d"), Some(Duration::days(1)));
+    assert_eq!(parse("45s"), Some(Duration::seconds(45)));
+  }
+
+  /// Plain numbers are minutes, like the old ban_length
+  #[test]
+  fn bare_minutes() {
+    assert_eq!(parse("5"), Some(Duration::minutes(5)));
+  }
+
+  #[test]
+  fn compound() {
+    assert_eq!(parse("1h30m"), Some(Duration::minutes(90)));
+    assert_eq!(parse("1w2d"), Some(Duration::days(9)));
+  }
+
+  #[test]
+  fn garbage() {
+    assert_eq!(parse(""), None);
+    assert_eq!(parse("m"), None);
+    assert_eq!(parse("5y"), None);
+    assert_eq!(parse("fish"), None);
+    assert_eq!(parse("1h30"), None);
+  }
+
+  /// Huge numbers are refused, not overflowed
+  #[test]
+  fn too_long() {
+    assert_eq!(parse("99999999999999999999m"), None);
+    assert_eq!(parse("9223372036854775807"), None);
+    assert_eq!(parse("9223372036854775807w"), None);
+    assert_eq!(parse("153722867280912930m1s"), None);
+    assert_eq!(parse("36500d"), Some(Duration::days(36500)));
+    assert_eq!(parse("36500d1s"), None);
+  }
+
+  #[test]
+  fn round_trip() {
+    assert_eq!(format(Duration::minutes(90)).as_slice(), "1h30m");
+    assert_eq!(format(Duration::days(1)).as_slice(), "1d");
+    assert_eq!(format(Duration::zero()).as_slice(), "0s");
+    assert_eq!(parse(format(Duration::minutes(5)).as_slice()), Some(Duration::minutes(5)));
+  }
+}
@@ -0,0 +1,25 @@
+use std::io::{File, IoResult, fs};
+
+/// Replaces a file's contents through a temp file next to it,
+/// so a crash mid-write leaves the old contents rather than half of the new.
+pub fn replace(path: &Path, contents: &str) -> IoResult<()> {
+  let tmp = path.with_extension("tmp");
+  try!(File::create(&tmp).write_str(contents));
+  fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::{File, TempDir};
+  use super::replace;
+
+  #[test]
+  fn replaces() {
+    let dir = TempDir::new("nofunbot").unwrap();
+    let path = dir.path().join("store.json");
+    replace(&path, "old").unwrap();
+    replace(&path, "new").unwrap();
+    assert_eq!(File::open(&path).read_to_string().unwrap().as_slice(), "new");
+    assert!(!path.with_extension("tmp").exists());
+  }
+}
@@ -0,0 +1,109 @@
+use chrono;
+use chrono::{DateTime, UTC};
+use duration;
+use std::collections::{Deque, RingBuf};
+
+/// How many messages from before an offence go into reports about it.
+pub static CONTEXT_LINES: uint = 3;
+
+/// Something somebody said, kept around so mods can see what led up to an offence.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Message {
+  pub time: DateTime<UTC>,
+  pub channel: String,
+  pub nick: String,
+  pub text: String
+}
+
+impl Message {
+  pub fn new(channel: &str, nick: &str, text: &str) -> Message {
+    Message {
+      time: chrono::UTC::now(),
+      channel: channel.to_string(),
+      nick: nick.to_string(),
+      text: text.to_string()
+    }
+  }
+  /// e.g. "2m ago in #chan <fred> kappa"
+  pub fn describe(&self) -> String {
+    format!("{} ago in {} <{}> {}",
+            duration::format(chrono::UTC::now() - self.time),
+            self.channel,
+            self.nick,
+            self.text)
+  }
+}
+
+/// The last few messages, oldest first. Once it's full, the oldest fall off
+/// as new ones come in.
+#[deriving(Clone)]
+pub struct History {
+  messages: RingBuf<Message>,
+  capacity: uint
+}
+
+impl History {
+  pub fn new(capacity: uint) -> History {
+    History { messages: RingBuf::with_capacity(capacity), capacity: capacity }
+  }
+  pub fn push(&mut self, message: Message) {
+    if self.messages.len() >= self.capacity {
+      self.messages.pop_front();
+    }
+    self.messages.push_back(message);
+  }
+  pub fn len(&self) -> uint {
+    self.messages.len()
+  }
+  /// The newest `count` messages, oldest first.
+  pub fn last(&self, count: uint) -> Vec<Message> {
+    let skip = self.messages.len() - count.min(self.messages.len());
+    self.messages.iter().skip(skip).map(|m| m.clone()).collect()
+  }
+  /// Up to `count` messages from just before `message`, oldest first.
+  /// Empty if `message` has fallen off already.
+  pub fn leading_up_to(&self, message: &Message, count: uint) -> Vec<Message> {
+    let messages: Vec<&Message> = self.messages.iter().collect();
+    match messages.iter().rposition(|&m| m == message) {
+      Some(idx) => messages.slice_to(idx).iter()
+        .skip(idx - count.min(idx))
+        .map(|&m| m.clone())
+        .collect(),
+      None => Vec::new()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{History, Message};
+
+  fn history(texts: &[&str]) -> History {
+    let mut history = History::new(3);
+    for text in texts.iter() {
+      history.push(Message::new("#test", "fred", *text));
+    }
+    history
+  }
+
+  fn texts(messages: Vec<Message>) -> Vec<String> {
+    messages.move_iter().map(|m| m.text).collect()
+  }
+
+  #[test]
+  fn oldest_fall_off() {
+    let history = history(["a", "b", "c", "d"]);
+    assert_eq!(history.len(), 3);
+    assert_eq!(texts(history.last(5)), vec!["b".to_string(), "c".to_string(), "d".to_string()]);
+    assert_eq!(texts(history.last(1)), vec!["d".to_string()]);
+  }
+
+  #[test]
+  fn leading_up_to() {
+    let history = history(["a", "b", "c"]);
+    let c = history.last(1).pop().unwrap();
+    assert_eq!(texts(history.leading_up_to(&c, 1)), vec!["b".to_string()]);
+    assert_eq!(texts(history.leading_up_to(&c, 5)), vec!["a".to_string(), "b".to_string()]);
+    assert!(history.leading_up_to(&Message::new("#test", "fred", "z"), 5).is_empty());
+  }
+}
@@ -0,0 +1,271 @@
+use std::uint;
+
+/// What the server says it supports, from 005 (RPL_ISUPPORT).
+/// Anything it doesn't mention keeps an RFC 1459-ish default.
+pub struct ISupport {
+  /// EXTBAN prefix (if any) and the extban types, e.g. (Some('$'), "ajrxz")
+  extban: Option<(Option<char>, String)>,
+  /// CHANMODES: list modes (like b), modes that always take a param,
+  /// modes that take one only when set, and plain flags.
+  chanmodes: [String, ..4],
+  /// PREFIX: (mode, symbol) pairs, highest rank first, e.g. ('o', '@')
+  prefixes: Vec<(char, char)>,
+  /// MAXLIST: list modes that share a limit, and the limit, e.g. ("beI", 100)
+  max_list: Vec<(String, uint)>,
+  /// MODES: how many mode changes with a param fit in one MODE line
+  modes: uint
+}
+
+impl ISupport {
+  pub fn new() -> ISupport {
+    ISupport {
+      extban: None,
+      chanmodes: ["b".to_string(), "k".to_string(), "l".to_string(), "imnpst".to_string()],
+      prefixes: vec![('o', '@'), ('v', '+')],
+      max_list: Vec::new(),
+      modes: 3
+    }
+  }
+
+  /// Reads the tokens from a 005 line.
+  /// Pass just the tokens, not our nick or the "are supported" text.
+  pub fn parse_tokens(&mut self, tokens: &[String]) {
+    for token in tokens.iter() {
+      let (key, value) = match token.as_slice().find('=') {
+        Some(idx) => (token.as_slice().slice_to(idx), token.as_slice().slice_from(idx + 1)),
+        None => (token.as_slice(), "")
+      };
+      match key {
+        "EXTBAN" => {
+          // "$,ajrxz", or ",ABCM" with no prefix
+          self.extban = match value.find(',') {
+            Some(idx) => Some((value.slice_to(idx).chars().next(), value.slice_from(idx + 1).to_string())),
+            None => None
+          };
+        },
+        "CHANMODES" => {
+          for (idx, modes) in value.split(',').take(4).enumerate() {
+            self.chanmodes[idx] = modes.to_string();
+          }
+        },
+        "MAXLIST" => {
+          // "bqeI:100" or "b:60,e:60"
+          self.max_list = value.split(',').filter_map(|entry| {
+            match entry.find(':') {
+              Some(idx) => from_str(entry.slice_from(idx + 1)).map(|n| (entry.slice_to(idx).to_string(), n)),
+              None => None
+            }
+          }).collect();
+        },
+        "MAXBANS" if self.max_list.is_empty() => {
+          // older servers; MAXLIST wins if we get both
+          match from_str(value) {
+            Some(n) => self.max_list = vec![("b".to_string(), n)],
+            None => ()
+          }
+        },
+        "MODES" => {
+          // no value means no limit, but the line length still is one
+          self.modes = if value.is_empty() { uint::MAX } else { from_str(value).unwrap_or(self.modes) };
+        },
+        "PREFIX" => {
+          // "(ov)@+"
+          match value.find(')') {
+            Some(idx) if value.starts_with("(") => {
+              self.prefixes = value.slice(1, idx).chars().zip(value.slice_from(idx + 1).chars()).collect();
+            },
+            _ => warn!("Ignoring weird PREFIX: {}", value)
+          }
+        },
+        _ => ()
+      }
+    }
+  }
+
+  /// How many mode changes we can send in one MODE line.
+  pub fn modes_per_line(&self) -> uint {
+    self.modes
+  }
+
+  /// Is this a list mode, like +b?
+  pub fn is_list_mode(&self, mode: char) -> bool {
+    self.chanmodes[0].as_slice().contains_char(mode)
+  }
+
+  /// How many entries a list mode can have, and which modes share that limit,
+  /// e.g. ("beI", 100) for 'b'. None if the server didn't say.
+  pub fn list_limit(&self, mode: char) -> Option<(String, uint)> {
+    self.max_list.iter().find(|&&(ref modes, _)| modes.as_slice().contains_char(mode)).map(|t| t.clone())
+  }
+
+  /// Does setting or unsetting this channel mode take a parameter?
+  pub fn mode_takes_param(&self, mode: char, adding: bool) -> bool {
+    self.is_prefix_mode(mode) ||
+      self.chanmodes[0].as_slice().contains_char(mode) ||
+      self.chanmodes[1].as_slice().contains_char(mode) ||
+      (adding && self.chanmodes[2].as_slice().contains_char(mode))
+  }
+
+  /// Is this a mode that gives channel status, like +o?
+  pub fn is_prefix_mode(&self, mode: char) -> bool {
+    self.prefixes.iter().any(|&(m, _)| m == mode)
+  }
+
+  /// Does this status mode make someone an op, or better? e.g. +o, or +q and +a where they outrank it.
+  pub fn is_op_mode(&self, mode: char) -> bool {
+    let op_rank = self.op_rank();
+    self.prefixes.iter().take(op_rank + 1).any(|&(m, _)| m == mode)
+  }
+
+  /// Splits a NAMES entry like "@+fred" into its status modes and nick, e.g. (['o', 'v'], "fred").
+  /// There can be several prefixes if the server does multi-prefix.
+  pub fn split_prefixes<'a>(&self, name: &'a str) -> (Vec<char>, &'a str) {
+    let mut modes = Vec::new();
+    for (idx, c) in name.char_indices() {
+      match self.prefixes.iter().find(|&&(_, symbol)| symbol == c) {
+        Some(&(mode, _)) => modes.push(mode),
+        None => return (modes, name.slice_from(idx))
+      }
+    }
+    (modes, "")
+  }
+
+  /// Does a NAMES entry like "@+fred" have ops, or better?
+  pub fn has_op_prefix(&self, name: &str) -> bool {
+    let (modes, _) = self.split_prefixes(name);
+    modes.iter().any(|&mode| self.is_op_mode(mode))
+  }
+
+  /// Do any of these status modes (e.g. "ov") rank at least as high as `min`?
+  pub fn outranks(&self, status: &str, min: char) -> bool {
+    let min_rank = match self.prefixes.iter().position(|&(m, _)| m == min) {
+      Some(rank) => rank,
+      None => return false
+    };
+    status.chars().any(|mode| self.prefixes.iter().take(min_rank + 1).any(|&(m, _)| m == mode))
+  }
+
+  /// Where +o is in PREFIX. If the server has no +o, only the top rank counts.
+  fn op_rank(&self) -> uint {
+    self.prefixes.iter().position(|&(m, _)| m == 'o').unwrap_or(0)
+  }
+
+  /// How to quiet a mask on this network, so they can read but not talk:
+  /// the mode to set and what to set it to, e.g. ('q', "*!*@host") or ('b', "~q:*!*@host").
+  /// None if the network can't, and callers should ban instead.
+  pub fn quiet(&self, mask: &str) -> Option<(char, String)> {
+    // on some networks +q is channel owner instead
+    if self.is_list_mode('q') && !self.is_prefix_mode('q') {
+      return Some(('q', mask.to_string()));
+    }
+    // Unreal's ~q:, then InspIRCd's m:
+    self.extban('q', mask).or_else(|| self.extban('m', mask)).map(|extban| ('b', extban))
+  }
+
+  /// Builds an extban like "$a:fred", if the server has that type.
+  pub fn extban(&self, kind: char, arg: &str) -> Option<String> {
+    match self.extban {
+      Some((prefix, ref kinds)) if kinds.as_slice().contains_char(kind) => Some(match prefix {
+        Some(prefix) => format!("{}{}:{}", prefix, kind, arg),
+        None => format!("{}:{}", kind, arg)
+      }),
+      _ => None
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::ISupport;
+
+  fn parse(tokens: &[&str]) -> ISupport {
+    let mut isupport = ISupport::new();
+    let tokens: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+    isupport.parse_tokens(tokens.as_slice());
+    isupport
+  }
+
+  #[test]
+  fn extban_with_prefix() {
+    let isupport = parse(["CHANTYPES=#", "EXTBAN=$,ajrxz"]);
+    assert_eq!(isupport.extban('a', "fred"), Some("$a:fred".to_string()));
+    assert_eq!(isupport.extban('q', "fred"), None);
+  }
+
+  #[test]
+  fn extban_without_prefix() {
+    let isupport = parse(["EXTBAN=,ABCMNOQRSTU"]);
+    assert_eq!(isupport.extban('R', "fred"), Some("R:fred".to_string()));
+  }
+
+  #[test]
+  fn quiet_list_mode() {
+    let isupport = parse(["CHANMODES=eIbq,k,flj,CFLMPQScgimnprstz", "PREFIX=(ov)@+"]);
+    assert_eq!(isupport.quiet("*!*@host"), Some(('q', "*!*@host".to_string())));
+  }
+
+  /// Unreal has +q for owners, and ~q: for quiets
+  #[test]
+  fn quiet_extban() {
+    let isupport = parse(["CHANMODES=beI,kfL,lj,psmntirRcOAQKVCuzNSMTGZ", "PREFIX=(qaohv)~&@%+", "EXTBAN=~,qjncrRa"]);
+    assert_eq!(isupport.quiet("*!*@host"), Some(('b', "~q:*!*@host".to_string())));
+  }
+
+  /// QuakeNet can't quiet at all
+  #[test]
+  fn no_quiet() {
+    let isupport = parse(["CHANMODES=b,k,l,imnpstrDducCNMT", "PREFIX=(ov)@+"]);
+    assert_eq!(isupport.quiet("*!*@host"), None);
+  }
+
+  #[test]
+  fn maxlist() {
+    let isupport = parse(["MAXLIST=bqeI:100"]);
+    assert_eq!(isupport.list_limit('q'), Some(("bqeI".to_string(), 100)));
+    assert_eq!(isupport.list_limit('k'), None);
+
+    let isupport = parse(["MAXLIST=b:60,e:40"]);
+    assert_eq!(isupport.list_limit('e'), Some(("e".to_string(), 40)));
+  }
+
+  #[test]
+  fn maxbans() {
+    let isupport = parse(["MAXBANS=45"]);
+    assert_eq!(isupport.list_limit('b'), Some(("b".to_string(), 45)));
+  }
+
+  #[test]
+  fn modes() {
+    assert_eq!(parse([]).modes_per_line(), 3);
+    assert_eq!(parse(["MODES=6"]).modes_per_line(), 6);
+  }
+
+  #[test]
+  fn op_status() {
+    let isupport = parse(["PREFIX=(qaohv)~&@%+"]);
+    assert!(isupport.is_op_mode('o'));
+    assert!(isupport.is_op_mode('a'));
+    assert!(!isupport.is_op_mode('h'));
+    assert!(isupport.has_op_prefix("@fred"));
+    assert!(isupport.has_op_prefix("~@fred"));
+    assert!(!isupport.has_op_prefix("%+fred"));
+    assert!(!isupport.has_op_prefix("fred"));
+  }
+
+  #[test]
+  fn multi_prefix() {
+    let isupport = parse(["PREFIX=(qaohv)~&@%+"]);
+    assert_eq!(isupport.split_prefixes("@%+fred"), (vec!['o', 'h', 'v'], "fred"));
+    assert_eq!(isupport.split_prefixes("fred"), (vec![], "fred"));
+    assert!(isupport.outranks("h", 'v'));
+    assert!(isupport.outranks("ov", 'v'));
+    assert!(!isupport.outranks("", 'v'));
+    assert!(!isupport.outranks("v", 'o'));
+  }
+
+  #[test]
+  fn no_extbans() {
+    let isupport = parse(["CHANTYPES=#"]);
+    assert_eq!(isupport.extban('a', "fred"), None);
+  }
+}
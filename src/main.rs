@@ -10,14 +10,19 @@ extern crate regex;
 extern crate flate;
 extern crate chrono;
 extern crate serialize;
+extern crate sqlite3;
 
 extern crate irc = "rust-irclib";
 
+use std::io::Timer;
 use std::str::IntoMaybeOwned;
+use std::task;
 
 pub use std::collections::HashMap;
 pub use usermanager::UserState;
 
+use chrono::Duration;
+
 use irc::conn::{
   Conn,
   Event,
@@ -27,23 +32,52 @@ use irc::conn::{
   Line,
 };
 
-use chrono::Duration;
-
+mod banbackend;
 mod banmanager;
 mod channelmanager;
+mod commands;
+mod duration;
+mod files;
+mod history;
+mod isupport;
+mod masks;
+mod modes;
+mod permissions;
 mod private; // private data
 mod rules;
 mod ticket;
 mod usermanager;
+mod userstore;
 
 fn main() {
   info!("nofunbot starting up...");
 
-  NoFunBot::launch(Config { 
+  let mut roles = HashMap::new();
+  roles.insert("account:XMPPwocky".to_string(), permissions::Admin);
+
+  supervise(Config { 
     nick: "NoFunBot".to_string(),
     server: "irc.quakenet.org".to_string(),
     port: 6667,
     nspass: private::NICKSERV_PASSWORD.to_string(),
+    ban_store: "bans.json".to_string(),
+    channel_store: "channels.json".to_string(),
+    user_store: "users.json".to_string(),
+    user_store_kind: userstore::FlatFile,
+    max_users: 20000,
+    user_history_len: 10,
+    channel_history_len: 30,
+    user_idle_mins: 24 * 60,
+    ban_escalation: 2.0,
+    strike_reset_days: 30,
+    kick_reason: Some("Temp-banned for {length}: {reason}".to_string()),
+    quiet_first: true,
+    command_prefix: "!".to_string(),
+    roles: roles,
+    default_role: permissions::Mod,
+    ticket_window_mins: 10,
+    ticket_expiry_mins: 24 * 60,
+    ticket_reminder_mins: 30,
   });
 }
 pub enum RulesCheckResult {
@@ -51,10 +85,6 @@ pub enum RulesCheckResult {
   Ticket((uint, uint), &'static str),
   RulesOK
 }
-pub enum CommandValidity {
-  CommandValid,
-  CommandNotValid
-}
 
 #[deriving(Clone, Decodable)]
 pub struct Config {
@@ -62,32 +92,181 @@ pub struct Config {
   server: String,
   port: u16,
   nspass: String,
+  /// Where bans are kept between restarts.
+  ban_store: String,
+  /// Where the channel list is kept, once it's been changed with join or part.
+  channel_store: String,
+  /// Where users' infraction histories are kept.
+  user_store: String,
+  user_store_kind: userstore::StoreKind,
+  /// Most users we keep track of at once. Past this, the longest-idle ones are forgotten.
+  /// Each keeps `user_history_len` messages, so this bounds how much chat we hold on to too.
+  max_users: uint,
+  /// How many of each user's messages we keep, for "context" and reports.
+  user_history_len: uint,
+  /// How many messages we keep for each channel, for what led up to an offence.
+  channel_history_len: uint,
+  /// Users who haven't talked for this many minutes are forgotten,
+  /// unless they have infractions we haven't saved.
+  user_idle_mins: i64,
+  /// Repeat bans of the same host last this many times longer each time.
+  ban_escalation: f64,
+  /// Repeat bans stop escalating once a host's gone this many days without one.
+  strike_reset_days: i64,
+  /// Kick banned users with this message, or don't kick if None.
+  /// "{length}" and "{reason}" get filled in.
+  kick_reason: Option<String>,
+  /// Quiet first offenders instead of banning them, where the network supports it.
+  quiet_first: bool,
+  /// Commands in control channels can start with this instead of our nick.
+  command_prefix: String,
+  /// Keyed by "account:name" or "host:example.com"; see `usermanager::key_for`.
+  roles: HashMap<String, permissions::Role>,
+  default_role: permissions::Role,
+  /// Repeat tickets for the same user and rule within this many minutes are merged.
+  ticket_window_mins: i64,
+  /// Unreviewed tickets are dropped after this many minutes without a new hit.
+  ticket_expiry_mins: i64,
+  /// Mods get reminded about tickets older than this many minutes.
+  ticket_reminder_mins: i64,
+}
+
+/// How often we check for expired bans, old tickets, etc.
+static TICK_SECS: i64 = 10;
+/// Sent as a PING every tick, so the PONG can be told apart from the server's own.
+static TICK_TOKEN: &'static str = "nofunbot-tick";
+/// How long queued mode changes wait for company before going out anyway.
+static MODE_BATCH_MS: i64 = 500;
+/// Sent as a PING when it's time to send the rest of the queued mode changes.
+static FLUSH_TOKEN: &'static str = "nofunbot-flush";
+/// How long to wait before starting over after the bot fails.
+static RESTART_SECS: i64 = 30;
+
+/// Runs the bot in its own task, starting it again if it fails,
+/// so one broken command or line can't take moderation down for good.
+/// Bans, channels and user records are saved as they change, so a fresh bot picks up where the old one left off.
+fn supervise(config: Config) {
+  loop {
+    let attempt = config.clone();
+    match task::try(proc() NoFunBot::launch(attempt)) {
+      Ok(()) => break,
+      Err(_) => {
+        error!("Bot failed, restarting in {}s...", RESTART_SECS);
+        Timer::new().unwrap().sleep(Duration::seconds(RESTART_SECS));
+      }
+    }
+  }
+}
+
+/// Other bots in our channels. We don't take orders from them or moderate them.
+fn is_ignored_bot(nick: &str) -> bool {
+  ["Crate", "goBot", "face", "YouTube", "weeedbot"].iter().any(|&n| n == nick)
 }
 
 pub struct NoFunBot {
   config: Config,
   banmgr: banmanager::BanManager,
   chanmgr: channelmanager::ChannelManager,
-  usermgr: usermanager::UserManager
+  usermgr: usermanager::UserManager,
+  perms: permissions::Permissions,
+  tickets: ticket::TicketManager,
+  isupport: isupport::ISupport,
+  /// Wakes up the flush timer, see `flush_modes`.
+  mode_flush: Sender<()>,
+  /// Whether the flush timer's already been woken up.
+  flush_requested: bool
 }
 
 impl NoFunBot {
   pub fn launch(config: Config) {
+    let (mode_flush, flush_rx) = channel();
     let mut bot = NoFunBot {
       config: config.clone(),
-      banmgr: banmanager::BanManager::new(),
-      chanmgr: channelmanager::ChannelManager::new(),
-      usermgr: usermanager::UserManager::new()
+      banmgr: banmanager::BanManager::new(Path::new(config.ban_store.as_slice()),
+                                               config.ban_escalation,
+                                               Duration::days(config.strike_reset_days),
+                                               config.kick_reason.clone(),
+                                               config.quiet_first),
+      chanmgr: channelmanager::ChannelManager::new(Path::new(config.channel_store.as_slice()),
+                                                   config.channel_history_len),
+      usermgr: usermanager::UserManager::new(userstore::open(config.user_store_kind,
+                                                             Path::new(config.user_store.as_slice())),
+                                             config.max_users,
+                                             config.user_history_len,
+                                             Duration::minutes(config.user_idle_mins)),
+      perms: permissions::Permissions::new(config.roles.clone(), config.default_role),
+      tickets: ticket::TicketManager::new(Duration::minutes(config.ticket_window_mins),
+                                          Duration::minutes(config.ticket_expiry_mins),
+                                          Duration::minutes(config.ticket_reminder_mins)),
+      isupport: isupport::ISupport::new(),
+      mode_flush: mode_flush,
+      flush_requested: false
     };
 
     let mut ircopts = irc::conn::Options::new(config.server.as_slice(), config.port);
     ircopts.nick = config.nick.as_slice();
 
+    // The connection only wakes us up when a line arrives, so PING the server
+    // on a timer and do our housekeeping when the PONG comes back.
+    let (ticks, tick_rx) = channel();
+    let flushes = ticks.clone();
+    spawn(proc() {
+      let mut timer = Timer::new().unwrap();
+      let periodic = timer.periodic(Duration::seconds(TICK_SECS));
+      loop {
+        periodic.recv();
+        let ping = irc::conn::SendCommand(IRCCmd("PING".into_maybe_owned()),
+                                          vec![TICK_TOKEN.as_bytes().to_vec()],
+                                          false);
+        if ticks.send_opt(ping).is_err() {
+          break; // connection's gone
+        }
+      }
+    });
+    // Likewise for mode changes that didn't fill a line: wait a bit for more,
+    // then PING, and send whatever we have when the PONG comes back.
+    spawn(proc() {
+      let mut timer = Timer::new().unwrap();
+      for () in flush_rx.iter() {
+        timer.sleep(Duration::milliseconds(MODE_BATCH_MS));
+        let ping = irc::conn::SendCommand(IRCCmd("PING".into_maybe_owned()),
+                                          vec![FLUSH_TOKEN.as_bytes().to_vec()],
+                                          false);
+        if flushes.send_opt(ping).is_err() {
+          break;
+        }
+      }
+    });
+    ircopts.commands = Some(tick_rx);
+
     match irc::conn::connect(ircopts, (), |c,e,_| bot.handle(c, e)) {
       Ok(()) => info!("Exiting normally..."),
       Err(err) => error!("Connection error: {}", err)
     }
   }
+  /// Expires old tickets, and nags the mods about ones still waiting.
+  pub fn update_tickets(&mut self, conn: &mut Conn) {
+    for ticket in self.tickets.expire().iter() {
+      info!("Ticket #{} expired", ticket.id());
+      self.chanmgr.log_for(conn, ticket.channel(), format!("Ticket #{} expired unreviewed: {}",
+                                                           ticket.id(),
+                                                           ticket.describe()).as_slice());
+    }
+    if !self.tickets.reminder_due() {
+      return;
+    }
+    // each control channel only hears about the channels it oversees
+    for control in self.chanmgr.control_channel_names().iter() {
+      let chanmgr = &self.chanmgr;
+      let reminder = self.tickets.reminder(|channel| {
+        chanmgr.control_channels_for(channel).iter().any(|name| name == control)
+      });
+      match reminder {
+        Some(reminder) => conn.privmsg(control.as_bytes(), reminder.as_bytes()),
+        None => ()
+      }
+    }
+  }
   pub fn handle(&mut self, conn: &mut Conn, event: Event) {
     match event {
       irc::conn::Connected => info!("Connected"),
@@ -95,29 +274,109 @@ impl NoFunBot {
       irc::conn::LineReceived(line) => self.handle_line(conn, line)
     }
   }
+  /// Periodic housekeeping: clear expired bans, forget idle users, etc.
+  /// Runs every TICK_SECS, whether or not anybody is talking.
+  pub fn tick(&mut self, conn: &mut Conn) {
+    self.banmgr.update();
+    self.chanmgr.retry_joins(conn);
+    self.update_tickets(conn);
+    self.usermgr.evict();
+  }
   pub fn handle_line(&mut self, conn: &mut Conn, line: Line) {
-    // clear expired bans, etc.
-    self.banmgr.update(conn);
+    self.dispatch_line(conn, line);
+    self.flush_modes(conn, false);
 
+    // pass on anything the ban manager ran into along the way
+    for &(ref channel, ref warning) in self.banmgr.take_warnings().iter() {
+      warn!("{}", warning);
+      self.chanmgr.log_for(conn, channel.as_slice(), warning.as_slice());
+    }
+  }
+  /// Sends the ban manager's queued mode changes, several to a line.
+  /// Partial lines are held back until the flush timer goes off, unless `all` is set.
+  fn flush_modes(&mut self, conn: &mut Conn, all: bool) {
+    if all {
+      self.flush_requested = false;
+    }
+    if self.banmgr.flush_modes(conn, all) && !self.flush_requested {
+      self.flush_requested = true;
+      self.mode_flush.send(());
+    }
+  }
+  fn dispatch_line(&mut self, conn: &mut Conn, line: Line) {
     match line {
       Line{command: IRCCode(1), ..} => {
         info!("Connected, IDing with nickserv");
-        conn.privmsg(b"Q@CServe.quakenet.org", format!("AUTH {} {}",
+        conn.privmsg(banbackend::Q_ADDRESS.as_bytes(), format!("AUTH {} {}",
                                                        self.config.nick,
                                                        self.config.nspass
                                                       ).as_bytes());
       },
-      Line{command: IRCCode(353), ref args, ..} => {
+      Line{command: IRCCode(5), ref args, ..} => {
+        // ISUPPORT: our nick, then tokens, then "are supported by this server"
+        if args.len() > 2 {
+          let tokens: Vec<String> = args.slice(1, args.len() - 1).iter()
+            .map(|t| String::from_utf8_lossy(t.as_slice()).into_string())
+            .collect();
+          self.isupport.parse_tokens(tokens.as_slice());
+          self.banmgr.set_list_limit(self.isupport.list_limit('b'));
+          self.banmgr.set_modes_per_line(self.isupport.modes_per_line());
+        }
+      },
+      Line{command: IRCCode(367), ref args, ..} if args.len() >= 3 => {
+        // ban list entry: our nick, channel, mask, and maybe who set it and when
+        let arg = |idx: uint| String::from_utf8_lossy(args[idx].as_slice()).into_string();
+        let set_by = if args.len() >= 4 { arg(3) } else { "unknown".to_string() };
+        self.banmgr.sync_ban(arg(1).as_slice(), arg(2).as_slice(), set_by.as_slice());
+      },
+      Line{command: IRCCode(368), ref args, ..} if args.len() >= 2 => {
+        // end of ban list
+        self.banmgr.end_sync(String::from_utf8_lossy(args[1].as_slice()).as_slice());
+      },
+      Line{command: IRCCode(478), ref args, ..} if args.len() >= 3 => {
+        // ERR_BANLISTFULL: our nick, channel, the mask that didn't fit
+        let channel = String::from_utf8_lossy(args[1].as_slice()).into_string();
+        let target = String::from_utf8_lossy(args[2].as_slice()).into_string();
+        self.banmgr.list_full(channel.as_slice(), target.as_slice());
+      },
+      Line{command: IRCCode(353), ref args, ..} if args.len() >= 4 => {
         // NAMES
-        // first 3 args are our nick, "=", channel name
-        args.as_slice().get(3).map(|names_bytes| String::from_utf8_lossy(names_bytes.as_slice()).to_string())
-          .map(|names|
-               for name in names.as_slice().split(' ').map(|s| regex!(r"^[@+]").replace_all(s, "")) { // space delimited
-                 self.chanmgr.find_mut(String::from_utf8_lossy(args[2].as_slice()).as_slice())
-            .map(|chan| chan.handle_join(name.as_slice()));
-               });
-      }
+        // first 3 args are our nick, "=", channel name, then space delimited names
+        // with status prefixes, e.g. "@+fred"
+        let channel = String::from_utf8_lossy(args[2].as_slice()).into_string();
+        let names = String::from_utf8_lossy(args[3].as_slice()).into_string();
+        let me = String::from_utf8_lossy(conn.me().nick()).into_string();
+        for name in names.as_slice().split(' ').filter(|name| !name.is_empty()) {
+          let (status, nick) = self.isupport.split_prefixes(name);
+          if nick == me.as_slice() {
+            let opped = self.isupport.has_op_prefix(name);
+            self.set_opped(conn, channel.as_slice(), opped, None);
+            if !opped {
+              self.ask_services(conn, channel.as_slice(), channelmanager::Op);
+            }
+          }
+          self.chanmgr.find_mut(channel.as_slice())
+            .map(|chan| chan.handle_names(nick, status.as_slice()));
+        }
+      },
+      Line{command: IRCCode(code), ref args, ..} if (code == 471 || code == 473 || code == 474 || code == 475) && args.len() >= 2 => {
+        // couldn't join: full, invite only, banned, or bad key
+        let channel = String::from_utf8_lossy(args[1].as_slice()).into_string();
+        self.join_failed(conn, code, channel.as_slice());
+      },
+      Line{command: IRCCode(482), ref args, ..} if args.len() >= 2 => {
+        // ERR_CHANOPRIVSNEEDED: something we did needed ops we don't have
+        let channel = String::from_utf8_lossy(args[1].as_slice()).into_string();
+        self.set_opped(conn, channel.as_slice(), false, None);
+        self.banmgr.not_opped(channel.as_slice());
+      },
       Line{command: IRCCmd(cmd), args, prefix: prefix } => match cmd.as_slice() {
+        "PONG" if args.iter().any(|arg| arg.as_slice() == TICK_TOKEN.as_bytes()) => {
+          self.tick(conn);
+        },
+        "PONG" if args.iter().any(|arg| arg.as_slice() == FLUSH_TOKEN.as_bytes()) => {
+          self.flush_modes(conn, true);
+        },
         "JOIN" if prefix.is_some() => {
           let prefix = prefix.unwrap();
           if prefix.nick() != conn.me().nick() {
@@ -139,6 +398,10 @@ impl NoFunBot {
           let chan = String::from_utf8_lossy(chan.as_slice());
           info!("JOINED: {}", chan);
           self.chanmgr.find_mut(chan.as_slice()).map(|chan| chan.join_ok());
+          // NAMES will tell us if we have ops
+          self.banmgr.set_opped(chan.as_slice(), false);
+          self.banmgr.restore(chan.as_slice());
+          self.banmgr.request_ban_list(conn, chan.as_slice());
         },
         "PART" if prefix.is_some() => {
           let prefix = prefix.unwrap();
@@ -148,6 +411,35 @@ impl NoFunBot {
               .map(|chan| chan.handle_part(String::from_utf8_lossy(prefix.nick()).as_slice()));
           }
         },
+        "NICK" if prefix.is_some() && !args.is_empty() => {
+          let old = String::from_utf8_lossy(prefix.as_ref().unwrap().nick()).into_string();
+          let new = String::from_utf8_lossy(args[0].as_slice()).into_string();
+          self.chanmgr.handle_nick(old.as_slice(), new.as_slice());
+        },
+        "QUIT" if prefix.is_some() => {
+          let prefix = prefix.unwrap();
+          if prefix.nick() != conn.me().nick() {
+            self.chanmgr.handle_quit(String::from_utf8_lossy(prefix.nick()).as_slice());
+          }
+        },
+        "KICK" if args.len() >= 2 => {
+          let channel = String::from_utf8_lossy(args[0].as_slice()).into_string();
+          let victim = String::from_utf8_lossy(args[1].as_slice()).into_string();
+          let kicker = prefix.as_ref().map_or("the server".to_string(), |p| String::from_utf8_lossy(p.nick()).into_string());
+          // we might be on a fallback nick
+          if kicker.as_bytes() == conn.me().nick() {
+            self.banmgr.kick_confirmed(channel.as_slice());
+          }
+          if victim.as_bytes() == conn.me().nick() {
+            let reason = args.as_slice().get(2).map_or(String::new(), |r| String::from_utf8_lossy(r.as_slice()).into_string());
+            self.kicked(conn, channel.as_slice(), kicker.as_slice(), reason.as_slice());
+          } else {
+            self.chanmgr.find_mut(channel.as_slice()).map(|chan| chan.handle_part(victim.as_slice()));
+          }
+        },
+        "MODE" if args.len() >= 2 && args[0].as_slice().starts_with(b"#") => {
+          self.handle_mode(conn, prefix.as_ref(), args);
+        },
         "PRIVMSG" | "NOTICE" => {
           let (src, dst, msg) = match prefix {
             Some(_) if args.len() == 2 => {
@@ -169,6 +461,15 @@ impl NoFunBot {
           if msgs.as_slice().starts_with("You are now logged in as") {
             info!("NickServ OK, joining channels");
             self.chanmgr.join_channels(conn);
+          } else if srcs.as_slice() == banbackend::Q_NICK && self.banmgr.q_reply(msgs.as_slice()) {
+            // Q answering one of our ban requests
+          } else if cmd.as_slice() == "NOTICE" {
+            // NOTICEs must never be answered, so they're never commands.
+            // Channel notices still get moderated, though.
+            info!("Notice {} -> {}: {}", srcs, dsts, msgs);
+            if dsts.as_slice().starts_with("#") && !is_ignored_bot(srcs.as_slice()) {
+              self.moderate(conn, src, dsts.as_slice(), msgs);
+            }
           } else {
             self.handle_privmsg(conn, msgs, srcs, dsts, src)
           }
@@ -195,84 +496,234 @@ impl NoFunBot {
       _ => ()
     }
   }
+  /// Follows channel mode changes, so we know about bans we didn't set,
+  /// and stop timing ours if somebody lifts them.
+  fn handle_mode(&mut self, conn: &mut Conn, setter: Option<&irc::User>, args: Vec<Vec<u8>>) {
+    let args: Vec<String> = args.iter()
+      .map(|arg| String::from_utf8_lossy(arg.as_slice()).into_string())
+      .collect();
+    let channel = args[0].as_slice();
+    let setter = setter.map_or("the server".to_string(), |user| String::from_utf8_lossy(user.nick()).into_string());
+    // we might be on a fallback nick
+    let me = conn.me().nick().to_vec();
+    let by_me = setter.as_bytes() == me.as_slice();
+    if by_me {
+      self.banmgr.modes_confirmed(channel);
+    }
+
+    for change in modes::parse(&self.isupport, args[1].as_slice(), args.slice_from(2)).move_iter() {
+      match change {
+        modes::ModeChange { adding, mode: 'k', param } => {
+          self.chanmgr.find_mut(channel).map(|chan| chan.set_key(if adding { param.clone() } else { None }));
+        },
+        modes::ModeChange { adding, mode, param: Some(ref nick) } if self.isupport.is_prefix_mode(mode) => {
+          self.chanmgr.find_mut(channel).map(|chan| chan.handle_status(nick.as_slice(), mode, adding));
+          if self.isupport.is_op_mode(mode) && nick.as_bytes() == me.as_slice() {
+            self.set_opped(conn, channel, adding, Some(setter.as_slice()));
+          }
+        },
+        modes::ModeChange { adding: true, mode: 'b', param: Some(ref mask) } => {
+          if self.banmgr.ban_added(channel, mask.as_slice(), setter.as_slice()) {
+            info!("{} banned {} in {}", setter, mask, channel);
+          }
+        },
+        modes::ModeChange { adding: false, mode, param: Some(ref target) } if self.isupport.is_list_mode(mode) => {
+          match self.banmgr.ban_removed(channel, mode, target.as_slice()) {
+            // Q lifting a ban we gave it is Q doing its job
+            Some(ref ban) if ban.backend() == banbackend::QBackend && setter.as_slice() == banbackend::Q_NICK => (),
+            Some(ref ban) if !by_me && ban.set_by() == self.config.nick.as_slice() => {
+              self.chanmgr.log_for(conn, channel, format!("{} lifted our ban on {} in {} early ({})",
+                                                          setter, target, channel, ban.reason()).as_slice());
+            },
+            _ => ()
+          }
+        },
+        _ => ()
+      }
+    }
+  }
+  /// Our status in a channel changed. Lets the ban manager know whether it can act there,
+  /// and tells the mods if we lost ops.
+  fn set_opped(&mut self, conn: &mut Conn, channel: &str, opped: bool, by: Option<&str>) {
+    let was_opped = match self.chanmgr.find_mut(channel) {
+      Some(chan) => {
+        let was_opped = chan.is_opped();
+        chan.set_opped(opped);
+        was_opped
+      },
+      None => false
+    };
+    self.banmgr.set_opped(channel, opped);
+    if was_opped && !opped {
+      let by = by.map_or(String::new(), |nick| format!(" ({} deopped me)", nick));
+      self.chanmgr.log_for(conn, channel, format!("I lost ops in {}{}! Bans and kicks there will wait until I get them back.",
+                                                  channel, by).as_slice());
+    } else if opped && !was_opped {
+      info!("Opped in {}", channel);
+    }
+  }
+  /// Asks the channel's services for something, if it has any.
+  /// Returns who we asked, e.g. "Q".
+  fn ask_services(&mut self, conn: &mut Conn, channel: &str, request: channelmanager::ServiceRequest) -> Option<String> {
+    match self.chanmgr.find(channel).and_then(|chan| chan.get_services()) {
+      Some(services) => {
+        let (to, msg) = services.request(request, channel);
+        conn.privmsg(to.as_bytes(), msg.as_bytes());
+        Some(services.name().to_string())
+      },
+      None => None
+    }
+  }
+  /// We got kicked. Tell the mods, and rejoin after a while.
+  fn kicked(&mut self, conn: &mut Conn, channel: &str, by: &str, reason: &str) {
+    let rejoin = self.chanmgr.find_mut(channel).and_then(|chan| { chan.kicked(); chan.next_join_in() })
+      .map_or(String::new(), |wait| format!(" Rejoining in {}.", duration::format(wait)));
+    self.banmgr.set_opped(channel, false);
+    self.chanmgr.log_for(conn, channel, format!("{} kicked me from {} ({}).{}",
+                                                by, channel, reason, rejoin).as_slice());
+  }
+  /// A JOIN didn't work. Asks services to let us in if we can, and tells the mods;
+  /// the join gets retried on a timer either way.
+  fn join_failed(&mut self, conn: &mut Conn, code: uint, channel: &str) {
+    let (why, request) = match code {
+      471 => ("it's full", channelmanager::Invite),
+      473 => ("it's invite only", channelmanager::Invite),
+      474 => ("I'm banned", channelmanager::Unban),
+      _ => ("I don't have the right key", channelmanager::Invite)
+    };
+    // services get one go per backoff step
+    let hurry = self.chanmgr.find_mut(channel)
+      .map_or(false, |chan| chan.get_services().is_some() && chan.retry_soon());
+    let asked = if hurry {
+      self.ask_services(conn, channel, request)
+        .map_or(String::new(), |services| format!(" Asked {} to let me in.", services))
+    } else {
+      String::new()
+    };
+    let retry = self.chanmgr.find(channel).and_then(|chan| chan.next_join_in())
+      .map_or(String::new(), |wait| format!(" Trying again in {}.", duration::format(wait)));
+    warn!("Couldn't join {}: {}", channel, why);
+    self.chanmgr.log_for(conn, channel, format!("Couldn't join {}: {}.{}{}", channel, why, asked, retry).as_slice());
+  }
+  /// Ops and voices (or whatever the channel's set to) aren't moderated.
+  fn is_exempt(&self, channel: &str, nick: &str) -> bool {
+    match self.chanmgr.find(channel) {
+      Some(chan) => match (chan.get_exempt_status(), chan.status(nick)) {
+        (Some(min), Some(status)) => self.isupport.outranks(status, min),
+        _ => false
+      },
+      None => false
+    }
+  }
+  /// Who sets and lifts bans in a channel. Channels we don't know get channel modes.
+  pub fn ban_backend(&self, channel: &str) -> banbackend::BackendKind {
+    self.chanmgr.find(channel).map_or(banbackend::ModeBackend, |chan| chan.get_ban_backend())
+  }
+  /// "Banned", or "Will ban" if it has to wait until we have ops.
+  pub fn ban_verb(&self, channel: &str, kind: &banmanager::BanKind) -> &'static str {
+    if self.banmgr.can_apply(channel, kind, self.ban_backend(channel)) { kind.verb() } else { kind.pending_verb() }
+  }
   pub fn handle_privmsg(&mut self, conn: &mut Conn, msg: String, src: String, dst: String, srcuser: &irc::User) {
     info!("{} -> {}: {}", src, dst, msg);
 
-    if ["Crate", "goBot", "face", "YouTube", "weeedbot"].iter().find(|&&n| n == src.as_slice()).is_some() {
+    if is_ignored_bot(src.as_slice()) {
       debug!("ignoring bot...");
       return;
     }
 
-    // moderating privmsgs is a shitty idea
-    if dst.as_slice().starts_with("#") {
-      if self.chanmgr.nick_is_mod(src.as_slice()) && 
-        msg.as_slice().starts_with(self.config.nick.as_slice()) {
+    let is_mod = self.chanmgr.nick_is_mod(src.as_slice());
+    if is_mod {
+      match self.command_text(msg.as_slice(), dst.as_slice()) {
+        Some(text) => {
           // we are being addressed!
           // m'lady
 
           // split on ' ', ignoring superfluous whitespace
-          let mut args: Vec<&str> = msg.as_slice().trim_chars(' ')
-            .split(' ').collect();
+          let args: Vec<&str> = text.split(' ').filter(|s| !s.is_empty()).collect();
 
           debug!("{}", args);
 
-          // "NoFunBot:"
-          if args.len() > 0 {
-            *args.get_mut(0) = args[0].trim_right_chars(':');
-            match self.handle_command(conn, src.as_slice(), dst.as_slice(), args.slice_from(1)) {
-              CommandValid => (),
-              CommandNotValid => {
-                conn.privmsg(src.as_bytes(), b"Unrecognized command, I'm a fish");
-              }
-            }
+          self.handle_command(conn, src.as_slice(), srcuser, dst.as_slice(), args.as_slice());
+          return
+        },
+        None => ()
+      }
+    }
 
-            return
-          }
-        } else {
-          self.moderate(conn, srcuser, dst.as_slice(), msg)
-        }
+    // moderating privmsgs is a shitty idea
+    if dst.as_slice().starts_with("#") {
+      self.moderate(conn, srcuser, dst.as_slice(), msg)
+    }
+  }
+  /// If a message is a command for us, returns it without the "NoFunBot:" or prefix.
+  /// Commands can address us by nick anywhere, or use the prefix in control channels.
+  /// Anything sent to us privately is a command.
+  fn command_text<'a>(&self, msg: &'a str, dst: &str) -> Option<&'a str> {
+    let nick = self.config.nick.as_slice();
+    let prefix = self.config.command_prefix.as_slice();
+
+    if msg.starts_with(nick) {
+      // "NoFunBot: stopword" or "NoFunBot, stopword"
+      Some(msg.slice_from(nick.len()).trim_left_chars([':', ','].as_slice()))
+    } else if !prefix.is_empty() && msg.starts_with(prefix) &&
+      (!dst.starts_with("#") || self.chanmgr.find(dst).map_or(false, |ch| ch.is_control())) {
+      Some(msg.slice_from(prefix.len()))
+    } else if !dst.starts_with("#") {
+      Some(msg)
+    } else {
+      None
     }
   }
   pub fn moderate(&mut self, conn: &mut Conn, user: &irc::User, channel: &str, msg: String) {
     let nick = String::from_utf8_lossy(user.nick()).to_string();
+    // picks up their record, if they've been here before
+    self.usermgr.seen(user);
+    // the channel hears everything, so reports can show what led up to an offence
+    let message = history::Message::new(channel, nick.as_slice(), msg.as_slice());
+    self.chanmgr.find_mut(channel).map(|ch| ch.remember(message.clone()));
+    if self.is_exempt(channel, nick.as_slice()) {
+      self.remember_message(user, message);
+      return;
+    }
     // early stopword check
     let stopword_detected = self.chanmgr.find(channel).and_then(|ch| ch.get_stopword())
       .filtered(|&stopword| msg.as_slice().contains(stopword)).is_some();
     
     if stopword_detected {
-      self.chanmgr.log_to_control_channels(conn, format!("Banned {} for stopword violation", nick).as_slice());
-      self.banmgr.ban(conn, channel, user);
+      let me = self.config.nick.clone();
+      let (kind, _) = self.ban_user(conn, channel, user, None, "Stopword violation", me.as_slice());
+      self.chanmgr.log_for(conn, channel, format!("{} {} for stopword violation",
+                                                  self.ban_verb(channel, &kind), nick).as_slice());
+      let mut context = self.context_for(channel, &message);
+      context.push(message.clone());
+      self.log_context(conn, channel, context.as_slice());
       self.chanmgr.find_mut(channel).map(|ch| ch.set_stopword(None));
     };
 
-    let userstate = self.usermgr.get_or_create(nick.as_slice());
-
-    match rules::check(msg.as_slice(), userstate) {
+    let result = rules::check(msg.as_slice(), self.usermgr.get_or_create(nick.as_slice()));
+    match result {
       Infraction(warn_msg) => {
-        // that's a paddlin'
-        userstate.infractions += 1;
-        info!("{} now has {} infractions...", nick, userstate.infractions);
-
-        if userstate.infractions < 3 {
-          // let them off w/ a warning
-          conn.privmsg(nick.as_bytes(), format!("{} Please read the channel rules: http://goo.gl/4T6EZR . After {} more infraction{}, you will be banned for {}m!",
-                                                warn_msg,
-                                                3 - userstate.infractions,
-                                                if 3 - userstate.infractions == 1 {""} else {"s"},
-                                                self.banmgr.get_ban_length().num_minutes()
-                                               ).as_bytes());
-          self.chanmgr.log_to_control_channels(conn, format!("Warning {}: {} {} infractions.", nick, warn_msg, userstate.infractions).as_slice()); 
-        } else {
-          info!("Kicking!");
-
-          userstate.infractions = 0;
-          self.chanmgr.log_to_control_channels(conn, format!("Banning {}: {}", nick, warn_msg).as_slice());
-          self.banmgr.ban(conn, channel, user);
+        if self.infraction(conn, user, channel, warn_msg) {
+          let mut context = self.context_for(channel, &message);
+          context.push(message.clone());
+          self.log_context(conn, channel, context.as_slice());
         }
       },
       Ticket((start, end), warn_msg) => {
+        let context = self.context_for(channel, &message);
+        let (id, hits) = {
+          let ticket = self.tickets.open(channel, user, msg.as_slice(), warn_msg, context.clone());
+          (ticket.id(), ticket.hits())
+        };
+        if hits > 1 {
+          // already reported, don't spam the mods
+          self.chanmgr.log_for(conn, channel, format!("Ticket #{}: {} did it again ({} times now)",
+                                                      id, nick, hits).as_slice());
+          return self.remember_message(user, message);
+        }
+
         // display fancy colors around matching portion
-        let header = format!("Infraction detected: {} said \"", nick);
+        let header = format!("Ticket #{}: {} said \"", id, nick);
         let mut buf = Vec::from_slice(header.as_bytes());
         let mut offset = buf.len();
         buf = buf.append(msg.as_bytes());
@@ -283,57 +734,147 @@ impl NoFunBot {
         buf.insert(end + offset, 0x03); offset += 1;
         let buflen = buf.len(); // oh hi borrowck
         buf.insert(buflen, '"' as u8); // close quote
-        self.chanmgr.log_to_control_channels_bytes(conn, buf.as_slice());
+        self.chanmgr.log_for_bytes(conn, channel, buf.as_slice());
+        self.log_context(conn, channel, context.as_slice());
       }
       RulesOK => ()
     }
 
-    userstate.last_message_time = chrono::UTC::now();
-    userstate.last_message = msg;
+    self.remember_message(user, message);
   }
-  pub fn handle_command(&mut self, conn: &mut Conn, src: &str, dst: &str, args: &[&str]) -> CommandValidity {
-    match args {
-      ["stopword", ..words] => {
-        let mut word = words.iter().fold(String::new(), |state, elem| {state.append(*elem).append(" ") });
-        word.pop_char(); // remove trailing space
-
-        conn.privmsg(dst.as_bytes(), format!("Okay, {}, next person to say {} gets kickbanned!",
-                                             src,
-                                             word).as_slice().as_bytes());
+  fn remember_message(&mut self, user: &irc::User, message: history::Message) {
+    let userstate = self.usermgr.seen(user);
+    userstate.last_message_time = message.time.clone();
+    userstate.last_message = message.text.clone();
+    userstate.recent.push(message);
+  }
+  /// What was said in a channel just before a message, for reports.
+  fn context_for(&self, channel: &str, message: &history::Message) -> Vec<history::Message> {
+    self.chanmgr.find(channel)
+      .map_or(Vec::new(), |ch| ch.recent().leading_up_to(message, history::CONTEXT_LINES))
+  }
+  /// Shows the mods what led up to something, indented under the report about it.
+  pub fn log_context(&self, conn: &mut Conn, channel: &str, context: &[history::Message]) {
+    for message in context.iter() {
+      self.chanmgr.log_for(conn, channel, format!("  {}", message.describe()).as_slice());
+    }
+  }
+  /// The mask we'd ban a user from a channel with.
+  pub fn ban_mask(&self, channel: &str, user: &irc::User) -> String {
+    let strategy = self.chanmgr.find(channel).map_or(masks::HostMask, |ch| ch.get_mask_strategy());
+    masks::mask_for(strategy, user, &self.isupport)
+  }
+  /// What the next automatic punishment for a mask would be:
+  /// a quiet for first offences if we can, otherwise a ban.
+  fn next_punishment(&self, channel: &str, mask: &str) -> banmanager::BanKind {
+    if self.banmgr.should_quiet(channel, mask) {
+      match self.isupport.quiet(mask) {
+        Some((mode, target)) => return banmanager::Quiet(mode, target),
+        None => debug!("Can't quiet on this network, banning instead")
+      }
+    }
+    banmanager::FullBan
+  }
+  /// Bans a user from a channel, and kicks them if we're set up to.
+  /// With no length they get the usual punishment: maybe a quiet for a first offence,
+  /// and longer bans if they've been banned before.
+  /// Returns what they got and for how long.
+  pub fn ban_user(&mut self, conn: &mut Conn, channel: &str, user: &irc::User,
+                  length: Option<Duration>, reason: &str, set_by: &str) -> (banmanager::BanKind, Duration) {
+    let mask = self.ban_mask(channel, user);
+    let backend = self.ban_backend(channel);
+    let (kind, length) = match length {
+      Some(length) => {
+        self.banmgr.apply(banmanager::Ban::new(channel, mask, banmanager::FullBan, length, reason, set_by), backend);
+        (banmanager::FullBan, length)
+      },
+      None => {
+        let kind = self.next_punishment(channel, mask.as_slice());
+        (kind.clone(), self.banmgr.ban(channel, mask, kind, reason, set_by, backend))
+      }
+    };
+    if kind == banmanager::FullBan {
+      self.banmgr.kick(channel, user.nick(), length, reason);
+    }
+    self.usermgr.banned(user, chrono::UTC::now() + length);
+    (kind, length)
+  }
+  /// Counts an infraction against a user: warns them, or bans them once they've had enough warnings.
+  /// Returns whether they got banned.
+  pub fn infraction(&mut self, conn: &mut Conn, user: &irc::User, channel: &str, warn_msg: &str) -> bool {
+    let nick = String::from_utf8_lossy(user.nick()).to_string();
 
-        self.chanmgr.find_mut(dst).expect("Channel not found!").set_stopword(Some(word));
+    // that's a paddlin'
+    let infractions = self.usermgr.add_infraction(user, channel, warn_msg);
+    info!("{} now has {} infractions...", nick, infractions);
+
+    if infractions < 3 {
+      // let them off w/ a warning
+      let mask = self.ban_mask(channel, user);
+      let punishment = match self.next_punishment(channel, mask.as_slice()) {
+        banmanager::FullBan => "banned",
+        banmanager::Quiet(..) => "muted"
+      };
+      conn.privmsg(nick.as_bytes(), format!("{} Please read the channel rules: http://goo.gl/4T6EZR . After {} more infraction{}, you will be {} for {}!",
+                                            warn_msg,
+                                            3 - infractions,
+                                            if 3 - infractions == 1 {""} else {"s"},
+                                            punishment,
+                                            duration::format(self.banmgr.next_ban_length(channel, mask.as_slice()))
+                                           ).as_bytes());
+      self.chanmgr.log_for(conn, channel, format!("Warning {}: {} {} infractions.", nick, warn_msg, infractions).as_slice()); 
+      false
+    } else {
+      info!("Kicking!");
+
+      let me = self.config.nick.clone();
+      let (kind, length) = self.ban_user(conn, channel, user, None, warn_msg, me.as_slice());
+      self.chanmgr.log_for(conn, channel, format!("{} {} for {}: {}",
+                                                  self.ban_verb(channel, &kind), nick, duration::format(length), warn_msg).as_slice());
+      true
+    }
+  }
+  pub fn handle_command(&mut self, conn: &mut Conn, src: &str, user: &irc::User, dst: &str, args: &[&str]) {
+    let (name, rest) = match args {
+      [name, ..rest] => (name, rest),
+      [] => return
+    };
+    let command = match commands::find(name) {
+      Some(command) => command,
+      None => {
+        warn!("Unknown command from {}: {}", src, args);
+        conn.privmsg(src.as_bytes(), format!("Unknown command \"{}\". Try \"help\".", name).as_bytes());
+        return;
+      }
+    };
 
-        CommandValid
-      },
-        ["clear_stopword"] => { 
-          conn.privmsg(dst.as_bytes(), b"Stopword cleared."); 
-          self.chanmgr.find_mut(dst).expect("Channel not found!").set_stopword(None);
-          CommandValid
-        },
-        ["forgive", target_nick] => {
-          info!("Forgiving {} by {}'s request...", target_nick, src)
-            self.chanmgr.log_to_control_channels(conn, format!("{} forgave {}...", src, target_nick).as_slice());
-          self.usermgr.get_or_create(target_nick).infractions = 0;
-          CommandValid
-        },
-        ["ban_length", len_str] => {
-          match std::from_str::FromStr::from_str(len_str) {
-            Some(len) => {
-              self.banmgr.set_ban_length(Duration::minutes(len));
-              self.chanmgr.log_to_control_channels(conn, format!("{} set ban length to {}m", src, len_str).as_slice());
-              CommandValid
-            },
-            None => {
-              warn!("Invalid ban length!");
-              CommandNotValid
-            }
-          }
-        },
-        _ => {
-          warn!("Unknown command from {}: {}", src, args);
-          CommandNotValid
-        }
+    if !self.perms.allows(user, command.role) {
+      warn!("{} ({}) tried {} without {} access", src, self.perms.role_of(user), args, command.role);
+      conn.privmsg(src.as_bytes(), format!("Sorry, {} needs {} access.", command.name, command.role).as_bytes());
+      // only the channel's own mods hear about it; a private attempt just gets logged
+      if dst.starts_with("#") {
+        self.chanmgr.log_for(conn, dst, format!("{} tried to use {} without {} access",
+                                                src, command.name, command.role).as_slice());
+      }
+      return;
     }
 
+    let parsed = match command.parse_args(rest) {
+      Ok(parsed) => parsed,
+      Err(err) => {
+        conn.privmsg(src.as_bytes(), format!("{} Usage: {}", err, command.usage()).as_bytes());
+        return;
+      }
+    };
+
+    let ctx = commands::Context {
+      src: src,
+      user: user,
+      channel: if dst.starts_with("#") { Some(dst) } else { None }
+    };
+    match (command.handler)(self, conn, &ctx, &parsed) {
+      Ok(()) => (),
+      Err(err) => conn.privmsg(src.as_bytes(), err.as_bytes())
+    }
   }
 }
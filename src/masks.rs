@@ -0,0 +1,168 @@
+use irc;
+use isupport::ISupport;
+
+/// How we turn a user into a ban mask.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub enum MaskStrategy {
+  /// *!*@host
+  HostMask,
+  /// *!*ident@host, so other people behind the same NAT or bouncer don't get hit
+  IdentHostMask,
+  /// nick!*@*
+  NickMask,
+  /// The user's QuakeNet account, as an extban if the server has one.
+  /// We only know the account from a +x host (see `account`), so on other networks,
+  /// or for users without +x, this is the same as HostMask.
+  AccountMask,
+  /// Picks from the above based on what the host looks like.
+  SmartMask
+}
+
+/// QuakeNet gives +x users a host of "<account>.users.quakenet.org".
+static QUAKENET_ACCOUNT_HOST: &'static str = ".users.quakenet.org";
+
+/// The mask to ban a user with.
+pub fn mask_for(strategy: MaskStrategy, user: &irc::User, isupport: &ISupport) -> String {
+  let nick = String::from_utf8_lossy(user.nick()).into_string();
+  let ident = user.user().map(|u| String::from_utf8_lossy(u).into_string());
+  let host = user.host().map(|h| String::from_utf8_lossy(h).into_string());
+  build(strategy,
+        nick.as_slice(),
+        ident.as_ref().map(|s| s.as_slice()),
+        host.as_ref().map(|s| s.as_slice()),
+        isupport)
+}
+
+fn build(strategy: MaskStrategy, nick: &str, ident: Option<&str>, host: Option<&str>, isupport: &ISupport) -> String {
+  let host = match host {
+    Some(host) => host,
+    // nothing better to go on
+    None => return format!("{}!*@*", nick)
+  };
+  // "~fred" means identd didn't answer, the ~ may come and go
+  let ident = ident.map_or("*".to_string(), |i| format!("*{}", i.trim_left_chars('~')));
+
+  match strategy {
+    HostMask => format!("*!*@{}", host),
+    IdentHostMask => format!("*!{}@{}", ident, host),
+    NickMask => format!("{}!*@*", nick),
+    AccountMask => match account(host).and_then(|acct| isupport.extban('a', acct)) {
+      Some(extban) => extban,
+      // an account host is as good as an account, and anything else is just a host
+      None => format!("*!*@{}", host)
+    },
+    SmartMask => {
+      if account(host).is_some() || host.contains_char('/') {
+        // account hosts and cloaks like "unaffiliated/fred" are one person
+        format!("*!*@{}", host)
+      } else if host.contains_char(':') {
+        ipv6_mask(host)
+      } else {
+        // plain IPs and hostnames might be shared
+        format!("*!{}@{}", ident, host)
+      }
+    }
+  }
+}
+
+/// The QuakeNet account name in a host, if the host gives it away.
+/// Other networks' cloaks don't reliably say whose they are, so they're just hosts.
+pub fn account<'a>(host: &'a str) -> Option<&'a str> {
+  if host.ends_with(QUAKENET_ACCOUNT_HOST) && host.len() > QUAKENET_ACCOUNT_HOST.len() {
+    Some(host.slice_to(host.len() - QUAKENET_ACCOUNT_HOST.len()))
+  } else {
+    None
+  }
+}
+
+/// Bans the whole /64, since one person usually gets all of it.
+/// If the address is too compressed to tell where the /64 ends, just ban the address.
+fn ipv6_mask(host: &str) -> String {
+  let head = match host.find_str("::") {
+    Some(idx) => host.slice_to(idx),
+    None => host
+  };
+  let groups: Vec<&str> = head.split(':').collect();
+  if groups.len() >= 4 {
+    format!("*!*@{}:*", groups.slice_to(4).connect(":"))
+  } else {
+    format!("*!*@{}", host)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use isupport::ISupport;
+  use super::{build, HostMask, IdentHostMask, NickMask, AccountMask, SmartMask};
+
+  #[test]
+  fn missing_host() {
+    let isupport = ISupport::new();
+    assert_eq!(build(HostMask, "fred", Some("~fred"), None, &isupport).as_slice(), "fred!*@*");
+    assert_eq!(build(SmartMask, "fred", None, None, &isupport).as_slice(), "fred!*@*");
+  }
+
+  #[test]
+  fn simple_strategies() {
+    let isupport = ISupport::new();
+    let host = Some("example.com");
+    assert_eq!(build(HostMask, "fred", Some("~fred"), host, &isupport).as_slice(), "*!*@example.com");
+    assert_eq!(build(IdentHostMask, "fred", Some("~fred"), host, &isupport).as_slice(), "*!*fred@example.com");
+    assert_eq!(build(NickMask, "fred", Some("~fred"), host, &isupport).as_slice(), "fred!*@*");
+  }
+
+  /// Without an ident, any ident will do
+  #[test]
+  fn missing_ident() {
+    let isupport = ISupport::new();
+    let host = Some("example.com");
+    assert_eq!(build(IdentHostMask, "fred", None, host, &isupport).as_slice(), "*!*@example.com");
+    assert_eq!(build(SmartMask, "fred", None, Some("1.2.3.4"), &isupport).as_slice(), "*!*@1.2.3.4");
+    assert_eq!(build(IdentHostMask, "fred", Some("~"), host, &isupport).as_slice(), "*!*@example.com");
+  }
+
+  /// Only QuakeNet's +x hosts give an account away
+  #[test]
+  fn account_elsewhere() {
+    let mut isupport = ISupport::new();
+    isupport.parse_tokens(["EXTBAN=$,ajrxz".to_string()]);
+    assert_eq!(build(AccountMask, "fred", None, Some("unaffiliated/fred"), &isupport).as_slice(),
+               "*!*@unaffiliated/fred");
+  }
+
+  #[test]
+  fn quakenet_account() {
+    let isupport = ISupport::new();
+    let host = Some("fred.users.quakenet.org");
+    assert_eq!(build(AccountMask, "fred", Some("~fred"), host, &isupport).as_slice(),
+               "*!*@fred.users.quakenet.org");
+    assert_eq!(build(SmartMask, "fred", Some("~fred"), host, &isupport).as_slice(),
+               "*!*@fred.users.quakenet.org");
+  }
+
+  #[test]
+  fn account_extban() {
+    let mut isupport = ISupport::new();
+    isupport.parse_tokens(["EXTBAN=$,ajrxz".to_string()]);
+    assert_eq!(build(AccountMask, "fred", None, Some("fred.users.quakenet.org"), &isupport).as_slice(),
+               "$a:fred");
+  }
+
+  #[test]
+  fn smart_ipv6() {
+    let isupport = ISupport::new();
+    assert_eq!(build(SmartMask, "fred", None, Some("2001:db8:1:2:3:4:5:6"), &isupport).as_slice(),
+               "*!*@2001:db8:1:2:*");
+    assert_eq!(build(SmartMask, "fred", None, Some("2001:db8::1"), &isupport).as_slice(),
+               "*!*@2001:db8::1");
+  }
+
+  #[test]
+  fn smart_shared_host() {
+    let isupport = ISupport::new();
+    assert_eq!(build(SmartMask, "fred", Some("~fred"), Some("1.2.3.4"), &isupport).as_slice(),
+               "*!*fred@1.2.3.4");
+    assert_eq!(build(SmartMask, "fred", Some("fred"), Some("unaffiliated/fred"), &isupport).as_slice(),
+               "*!*@unaffiliated/fred");
+  }
+}
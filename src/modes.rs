@@ -0,0 +1,286 @@
+use isupport::ISupport;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// One change from a MODE line, e.g. +b *!*@host
+#[deriving(Clone, PartialEq, Show)]
+pub struct ModeChange {
+  pub adding: bool,
+  pub mode: char,
+  pub param: Option<String>
+}
+
+/// Splits a channel MODE line into single changes.
+/// `modes` is e.g. "+bb-o", and `params` whatever came after it.
+/// Which modes take params comes from ISUPPORT.
+pub fn parse(isupport: &ISupport, modes: &str, params: &[String]) -> Vec<ModeChange> {
+  let mut changes = Vec::new();
+  let mut params = params.iter();
+  let mut adding = true;
+
+  for mode in modes.chars() {
+    match mode {
+      '+' => adding = true,
+      '-' => adding = false,
+      _ => {
+        let param = if isupport.mode_takes_param(mode, adding) {
+          match params.next() {
+            Some(param) => Some(param.clone()),
+            None => {
+              warn!("MODE {} is missing a param for {}", modes, mode);
+              continue;
+            }
+          }
+        } else {
+          None
+        };
+        changes.push(ModeChange { adding: adding, mode: mode, param: param });
+      }
+    }
+  }
+  changes
+}
+
+/// One MODE line's worth of changes to a channel.
+pub struct ModeLine {
+  pub channel: String,
+  pub changes: Vec<ModeChange>
+}
+
+/// Servers cut lines off at 512 bytes, and the prefix, command and channel need room too.
+static MAX_PARAM_BYTES: uint = 350;
+
+/// Mode changes waiting to go out, so several can share a MODE line.
+pub struct ModeQueue {
+  pending: Vec<(String, ModeChange)>,
+  /// Channels whose changes can't go out yet, e.g. because we're not opped there.
+  held: HashSet<String>
+}
+
+impl ModeQueue {
+  pub fn new() -> ModeQueue {
+    ModeQueue { pending: Vec::new(), held: HashSet::new() }
+  }
+  pub fn push(&mut self, channel: &str, change: ModeChange) {
+    self.pending.push((channel.to_string(), change));
+  }
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+  /// Holds on to a channel's changes until it's released again.
+  pub fn hold(&mut self, channel: &str, held: bool) {
+    if held {
+      self.held.insert(channel.to_string());
+    } else {
+      self.held.remove(&channel.to_string());
+    }
+  }
+  pub fn is_held(&self, channel: &str) -> bool {
+    self.held.contains_equiv(&channel)
+  }
+  /// Drops everything waiting for a channel, and whether it was held, e.g. when we leave it.
+  pub fn forget(&mut self, channel: &str) {
+    self.pending.retain(|&(ref c, _)| c.as_slice() != channel);
+    self.held.remove(&channel.to_string());
+  }
+  /// Is a change setting `mode` on `param` in a channel still waiting to go out?
+  pub fn is_pending(&self, channel: &str, mode: char, param: &str) -> bool {
+    self.pending.iter().any(|&(ref c, ref change)| {
+      c.as_slice() == channel && change.adding && change.mode == mode &&
+        change.param.as_ref().map_or(false, |p| p.as_slice() == param)
+    })
+  }
+  /// How many changes are waiting for a channel.
+  pub fn held_count(&self, channel: &str) -> uint {
+    self.pending.iter().filter(|&&(ref c, _)| c.as_slice() == channel).count()
+  }
+  /// Is anything waiting that could go out now?
+  pub fn has_sendable(&self) -> bool {
+    self.pending.iter().any(|&(ref channel, _)| !self.held.contains(channel))
+  }
+  /// Takes the queued changes as MODE lines of at most `per_line` changes each,
+  /// keeping each channel's changes in order. Unless `partial` is set,
+  /// changes that wouldn't fill a line stay queued, to wait for company.
+  /// Held channels' changes always stay queued.
+  pub fn take_lines(&mut self, per_line: uint, partial: bool) -> Vec<ModeLine> {
+    let mut order = Vec::new();
+    let mut by_channel: HashMap<String, Vec<ModeChange>> = HashMap::new();
+    for (channel, change) in mem::replace(&mut self.pending, Vec::new()).move_iter() {
+      if self.held.contains(&channel) {
+        self.pending.push((channel, change));
+        continue;
+      }
+      if !by_channel.contains_key(&channel) {
+        order.push(channel.clone());
+      }
+      by_channel.find_or_insert(channel, Vec::new()).push(change);
+    }
+
+    let mut lines = Vec::new();
+    for channel in order.move_iter() {
+      let mut line = Vec::new();
+      let mut bytes = 0;
+      for change in by_channel.pop(&channel).unwrap().move_iter() {
+        let len = change.param.as_ref().map_or(0, |param| param.len() + 1);
+        if !line.is_empty() && (line.len() >= per_line || bytes + len > MAX_PARAM_BYTES) {
+          lines.push(ModeLine { channel: channel.clone(), changes: mem::replace(&mut line, Vec::new()) });
+          bytes = 0;
+        }
+        bytes += len;
+        line.push(change);
+      }
+      if partial || line.len() >= per_line {
+        lines.push(ModeLine { channel: channel.clone(), changes: line });
+      } else {
+        for change in line.move_iter() {
+          self.pending.push((channel.clone(), change));
+        }
+      }
+    }
+    lines
+  }
+}
+
+impl ModeLine {
+  /// The changes as they go on the wire, e.g. ("+bb-b", [mask, mask, mask])
+  pub fn to_args(&self) -> (String, Vec<String>) {
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut adding = None;
+    for change in self.changes.iter() {
+      if adding != Some(change.adding) {
+        modes.push_char(if change.adding { '+' } else { '-' });
+        adding = Some(change.adding);
+      }
+      modes.push_char(change.mode);
+      match change.param {
+        Some(ref param) => params.push(param.clone()),
+        None => ()
+      }
+    }
+    (modes, params)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use isupport::ISupport;
+  use super::{parse, ModeChange, ModeQueue};
+
+  fn take(queue: &mut ModeQueue, per_line: uint, partial: bool) -> Vec<(String, String, Vec<String>)> {
+    queue.take_lines(per_line, partial).iter().map(|line| {
+      let (modes, params) = line.to_args();
+      (line.channel.clone(), modes, params)
+    }).collect()
+  }
+
+  fn change(adding: bool, mode: char, param: Option<&str>) -> ModeChange {
+    ModeChange { adding: adding, mode: mode, param: param.map(|p| p.to_string()) }
+  }
+
+  fn params(params: &[&str]) -> Vec<String> {
+    params.iter().map(|p| p.to_string()).collect()
+  }
+
+  #[test]
+  fn bans_and_ops() {
+    let isupport = ISupport::new();
+    assert_eq!(parse(&isupport, "+bo-b", params(["*!*@a", "fred", "*!*@b"]).as_slice()),
+               vec![change(true, 'b', Some("*!*@a")),
+                    change(true, 'o', Some("fred")),
+                    change(false, 'b', Some("*!*@b"))]);
+  }
+
+  /// +l takes a param when set, but not when unset
+  #[test]
+  fn limit() {
+    let isupport = ISupport::new();
+    assert_eq!(parse(&isupport, "+ml", params(["10"]).as_slice()),
+               vec![change(true, 'm', None), change(true, 'l', Some("10"))]);
+    assert_eq!(parse(&isupport, "-l", params([]).as_slice()),
+               vec![change(false, 'l', None)]);
+  }
+
+  #[test]
+  fn missing_params() {
+    let isupport = ISupport::new();
+    assert_eq!(parse(&isupport, "+bb", params(["*!*@a"]).as_slice()),
+               vec![change(true, 'b', Some("*!*@a"))]);
+  }
+
+  fn ban(adding: bool, mask: &str) -> ModeChange {
+    change(adding, 'b', Some(mask))
+  }
+
+  /// Five bans at three per line, and the signs only where they change
+  #[test]
+  fn batched_lines() {
+    let mut queue = ModeQueue::new();
+    for mask in ["*!*@a", "*!*@b", "*!*@c", "*!*@d"].iter() {
+      queue.push("#test", ban(true, *mask));
+    }
+    queue.push("#test", ban(false, "*!*@e"));
+
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#test".to_string(), "+bbb".to_string(), params(["*!*@a", "*!*@b", "*!*@c"])),
+                    ("#test".to_string(), "+b-b".to_string(), params(["*!*@d", "*!*@e"]))]);
+    assert!(queue.is_empty());
+  }
+
+  /// Without `partial`, a line that isn't full yet stays queued
+  #[test]
+  fn partial_lines_wait() {
+    let mut queue = ModeQueue::new();
+    for mask in ["*!*@a", "*!*@b", "*!*@c", "*!*@d"].iter() {
+      queue.push("#test", ban(false, *mask));
+    }
+
+    assert_eq!(take(&mut queue, 3, false),
+               vec![("#test".to_string(), "-bbb".to_string(), params(["*!*@a", "*!*@b", "*!*@c"]))]);
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#test".to_string(), "-b".to_string(), params(["*!*@d"]))]);
+  }
+
+  #[test]
+  fn channels_kept_apart() {
+    let mut queue = ModeQueue::new();
+    queue.push("#a", ban(true, "*!*@a"));
+    queue.push("#b", ban(true, "*!*@b"));
+    queue.push("#a", ban(true, "*!*@c"));
+
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#a".to_string(), "+bb".to_string(), params(["*!*@a", "*!*@c"])),
+                    ("#b".to_string(), "+b".to_string(), params(["*!*@b"]))]);
+  }
+
+  #[test]
+  fn held_channels() {
+    let mut queue = ModeQueue::new();
+    queue.hold("#a", true);
+    queue.push("#a", ban(true, "*!*@a"));
+    queue.push("#b", ban(true, "*!*@b"));
+
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#b".to_string(), "+b".to_string(), params(["*!*@b"]))]);
+    assert!(!queue.has_sendable());
+    assert!(!queue.is_empty());
+
+    queue.hold("#a", false);
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#a".to_string(), "+b".to_string(), params(["*!*@a"]))]);
+  }
+
+  #[test]
+  fn forgotten_channels() {
+    let mut queue = ModeQueue::new();
+    queue.hold("#a", true);
+    queue.push("#a", ban(true, "*!*@a"));
+    queue.push("#b", ban(true, "*!*@b"));
+
+    queue.forget("#a");
+    assert!(!queue.is_held("#a"));
+    assert_eq!(queue.held_count("#a"), 0);
+    assert_eq!(take(&mut queue, 3, true),
+               vec![("#b".to_string(), "+b".to_string(), params(["*!*@b"]))]);
+  }
+}
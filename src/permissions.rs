@@ -0,0 +1,72 @@
+use irc;
+use std::collections::HashMap;
+use usermanager;
+
+/// How far a mod is trusted. Roles are ordered, so a higher role
+/// can do anything a lower one can.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show, Decodable)]
+pub enum Role {
+  Helper,
+  Mod,
+  Admin
+}
+
+/// Maps mods to their roles. Mods are keyed the way `usermanager::key_for`
+/// names them ("account:name" or "host:example.com"), since anybody can
+/// take a nick.
+/// Anybody in a control channel without an entry gets `default_role`.
+pub struct Permissions {
+  roles: HashMap<String, Role>,
+  default_role: Role
+}
+
+impl Permissions {
+  pub fn new(roles: HashMap<String, Role>, default_role: Role) -> Permissions {
+    Permissions { roles: roles, default_role: default_role }
+  }
+
+  /// The role of a user. Only meaningful for users that are mods at all.
+  pub fn role_of(&self, user: &irc::User) -> Role {
+    usermanager::key_for(user).and_then(|key| self.roles.find(&key).map(|&role| role))
+      .unwrap_or(self.default_role)
+  }
+
+  pub fn allows(&self, user: &irc::User, required: Role) -> bool {
+    self.role_of(user) >= required
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use irc;
+  use std::collections::HashMap;
+  use super::{Permissions, Admin, Mod, Helper};
+
+  fn user(prefix: &str) -> irc::User {
+    irc::User::parse(prefix.as_bytes())
+  }
+
+  fn perms() -> Permissions {
+    let mut roles = HashMap::new();
+    roles.insert("account:Fred".to_string(), Admin);
+    roles.insert("host:mods.example.com".to_string(), Mod);
+    Permissions::new(roles, Helper)
+  }
+
+  #[test]
+  fn roles_follow_identity() {
+    let perms = perms();
+    assert_eq!(perms.role_of(&user("Fred!fred@Fred.users.quakenet.org")), Admin);
+    // renamed, still logged in
+    assert_eq!(perms.role_of(&user("Fred_away!fred@Fred.users.quakenet.org")), Admin);
+    assert_eq!(perms.role_of(&user("Jim!jim@mods.example.com")), Mod);
+  }
+
+  #[test]
+  fn nick_alone_is_not_enough() {
+    let perms = perms();
+    let impostor = user("Fred!fred@evil.example.com");
+    assert_eq!(perms.role_of(&impostor), Helper);
+    assert!(!perms.allows(&impostor, Admin));
+  }
+}
@@ -1,23 +1,293 @@
 use irc;
-use banmanager::Ban;
-use chrono::Duration;
+use chrono;
+use chrono::{Duration, DateTime, UTC};
+use duration;
+use history::Message;
 
+/// A message that partially matched a rule, waiting for a mod to look at it.
 pub struct Ticket {
+  id: uint,
   info_msg: String,
+  message: String,
   channel: String,
-  user: irc::User
+  user: irc::User,
+  opened: DateTime<UTC>,
+  /// Same user breaking the same rule again soon after gets counted here,
+  /// instead of opening another ticket.
+  hits: uint,
+  last_hit: DateTime<UTC>,
+  /// What was said in the channel just before it.
+  context: Vec<Message>
 }
 impl Ticket {
-  pub fn new(self, channel: String, user: irc::User, info_msg: String) -> Ticket {
+  pub fn new(id: uint, channel: String, user: irc::User, message: String, info_msg: String,
+             context: Vec<Message>) -> Ticket {
     Ticket {
+      id: id,
       channel: channel,
       user: user,
-      info_msg: info_msg
+      message: message,
+      info_msg: info_msg,
+      context: context,
+      opened: chrono::UTC::now(),
+      hits: 1,
+      last_hit: chrono::UTC::now()
     }
   }
 
-  /// Note: the ban is NOT applied for you!
-  pub fn to_ban(self, length: Duration) -> Ban {
-    Ban::new(self.channel.as_slice(), &self.user, length)
+  pub fn id(&self) -> uint {
+    self.id
+  }
+  pub fn nick(&self) -> String {
+    String::from_utf8_lossy(self.user.nick()).into_string()
+  }
+  pub fn user<'a>(&'a self) -> &'a irc::User {
+    &self.user
+  }
+  pub fn channel<'a>(&'a self) -> &'a str {
+    self.channel.as_slice()
+  }
+  pub fn info_msg<'a>(&'a self) -> &'a str {
+    self.info_msg.as_slice()
+  }
+  pub fn hits(&self) -> uint {
+    self.hits
+  }
+  pub fn context<'a>(&'a self) -> &'a [Message] {
+    self.context.as_slice()
+  }
+
+  /// One line summary for mods, e.g. "#3 fredbloggs in #chan 5m ago: "kappa" (This isn't Twitch chat.)"
+  pub fn describe(&self) -> String {
+    format!("#{} {} in {} {} ago: \"{}\" ({}){}",
+            self.id,
+            self.nick(),
+            self.channel,
+            duration::format(chrono::UTC::now() - self.opened),
+            self.message,
+            self.info_msg,
+            if self.hits > 1 { format!(" x{}", self.hits) } else { String::new() })
+  }
+
+  /// Is this ticket about the same thing as a new hit?
+  fn matches(&self, channel: &str, user: &irc::User, info_msg: &str) -> bool {
+    self.channel.as_slice() == channel &&
+      self.user.nick() == user.nick() &&
+      self.info_msg.as_slice() == info_msg
+  }
+
+}
+
+/// Open tickets, oldest first.
+pub struct TicketManager {
+  tickets: Vec<Ticket>,
+  next_id: uint,
+
+  /// Repeat hits inside this window go onto the existing ticket.
+  merge_window: Duration,
+  /// Unreviewed tickets are dropped once they've gone this long without a hit.
+  /// Counted from the last hit rather than from when it was opened, so a ticket
+  /// that's still collecting hits stays around for the mods.
+  expiry: Duration,
+  /// Remind mods once the oldest ticket is this old, and at most this often.
+  reminder_age: Duration,
+  last_reminder: DateTime<UTC>
+}
+impl TicketManager {
+  pub fn new(merge_window: Duration, expiry: Duration, reminder_age: Duration) -> TicketManager {
+    TicketManager {
+      tickets: Vec::new(),
+      next_id: 1,
+      merge_window: merge_window,
+      expiry: expiry,
+      reminder_age: reminder_age,
+      last_reminder: chrono::UTC::now()
+    }
+  }
+
+  /// Opens a ticket, or adds a hit to a recent one for the same user and rule.
+  /// `context` is what was said just before; only the first hit's is kept.
+  /// Returns the ticket.
+  pub fn open<'a>(&'a mut self, channel: &str, user: &irc::User, message: &str, info_msg: &str,
+                  context: Vec<Message>) -> &'a Ticket {
+    let now = chrono::UTC::now();
+    let window = self.merge_window;
+    match self.tickets.iter().position(|t| t.matches(channel, user, info_msg) && now - t.last_hit <= window) {
+      Some(idx) => {
+        let ticket = self.tickets.get_mut(idx);
+        ticket.hits += 1;
+        ticket.last_hit = now;
+        return ticket;
+      },
+      None => ()
+    }
+
+    let id = self.next_id;
+    self.next_id += 1;
+    self.tickets.push(Ticket::new(id, channel.to_string(), user.clone(),
+                                  message.to_string(), info_msg.to_string(), context));
+    self.tickets.last().unwrap()
+  }
+
+  /// Removes a ticket from the queue, e.g. once a mod has dealt with it.
+  pub fn take(&mut self, id: uint) -> Option<Ticket> {
+    match self.tickets.iter().position(|t| t.id == id) {
+      Some(idx) => self.tickets.remove(idx),
+      None => None
+    }
+  }
+
+  /// Drops a channel's tickets, e.g. when we leave it, and returns them.
+  pub fn close_channel(&mut self, channel: &str) -> Vec<Ticket> {
+    let (closed, open) = ::std::mem::replace(&mut self.tickets, Vec::new())
+      .partition(|t| t.channel.as_slice() == channel);
+    self.tickets = open;
+    closed
+  }
+
+  /// Drops tickets nobody got around to, and that haven't had a hit in a while,
+  /// and returns them. See `expiry`.
+  pub fn expire(&mut self) -> Vec<Ticket> {
+    let now = chrono::UTC::now();
+    let expiry = self.expiry;
+    let (expired, open) = ::std::mem::replace(&mut self.tickets, Vec::new())
+      .partition(|t| now - t.last_hit > expiry);
+    self.tickets = open;
+    expired
+  }
+
+  /// Is it time to nag the mods? True if a ticket has been sitting around
+  /// and we haven't nagged lately; see `reminder` for what to say to whom.
+  pub fn reminder_due(&mut self) -> bool {
+    let now = chrono::UTC::now();
+    if now - self.last_reminder < self.reminder_age {
+      return false;
+    }
+    let age = self.reminder_age;
+    if !self.tickets.iter().any(|t| now - t.opened >= age) {
+      return false;
+    }
+    self.last_reminder = now;
+    true
+  }
+
+  /// A nag about the tickets in channels `oversees` accepts.
+  /// Returns None if none of those have been sitting around.
+  pub fn reminder(&self, oversees: |&str| -> bool) -> Option<String> {
+    let now = chrono::UTC::now();
+    let tickets: Vec<&Ticket> = self.tickets.iter().filter(|t| oversees(t.channel.as_slice())).collect();
+    let oldest = match tickets.iter().min_by(|t| t.opened) {
+      Some(t) if now - t.opened >= self.reminder_age => t,
+      _ => return None
+    };
+    Some(format!("{} ticket{} waiting, the oldest (#{}) is {} old. Try \"tickets\".",
+                 tickets.len(),
+                 if tickets.len() == 1 {""} else {"s"},
+                 oldest.id,
+                 duration::format(now - oldest.opened)))
+  }
+
+  pub fn iter<'a>(&'a self) -> ::std::slice::Items<'a, Ticket> {
+    self.tickets.iter()
+  }
+
+  pub fn len(&self) -> uint {
+    self.tickets.len()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{Duration, UTC};
+  use irc;
+  use super::TicketManager;
+
+  fn manager() -> TicketManager {
+    TicketManager::new(Duration::minutes(10), Duration::hours(24), Duration::minutes(30))
+  }
+
+  fn user(prefix: &str) -> irc::User {
+    irc::User::parse(prefix.as_bytes())
+  }
+
+  fn open(mgr: &mut TicketManager, channel: &str, nick: &str, rule: &str) -> uint {
+    let user = user(format!("{}!~{}@example.com", nick, nick).as_slice());
+    mgr.open(channel, &user, "kappa", rule, Vec::new()).id()
+  }
+
+  /// Repeats inside the window pile onto one ticket; anything else opens a new one
+  #[test]
+  fn merge_window() {
+    let mut mgr = manager();
+    let first = open(&mut mgr, "#test", "fred", "emotes");
+    assert_eq!(open(&mut mgr, "#test", "fred", "emotes"), first);
+    assert!(open(&mut mgr, "#test", "fred", "caps") != first);
+    assert!(open(&mut mgr, "#other", "fred", "emotes") != first);
+    assert!(open(&mut mgr, "#test", "bob", "emotes") != first);
+    assert_eq!(mgr.len(), 4);
+    assert_eq!(mgr.iter().next().unwrap().hits(), 2);
+
+    // the window counts from the last hit
+    mgr.tickets.get_mut(0).last_hit = UTC::now() - Duration::minutes(11);
+    assert!(open(&mut mgr, "#test", "fred", "emotes") != first);
+    assert_eq!(mgr.len(), 5);
+  }
+
+  /// Tickets go once they've been quiet for the expiry, however old they are
+  #[test]
+  fn expiry() {
+    let mut mgr = manager();
+    let quiet = open(&mut mgr, "#test", "fred", "emotes");
+    let busy = open(&mut mgr, "#test", "bob", "emotes");
+    mgr.tickets.get_mut(0).opened = UTC::now() - Duration::hours(25);
+    mgr.tickets.get_mut(0).last_hit = UTC::now() - Duration::hours(25);
+    mgr.tickets.get_mut(1).opened = UTC::now() - Duration::hours(25);
+    mgr.tickets.get_mut(1).last_hit = UTC::now() - Duration::hours(1);
+
+    let expired: Vec<uint> = mgr.expire().iter().map(|t| t.id()).collect();
+    assert_eq!(expired, vec![quiet]);
+    assert_eq!(mgr.iter().map(|t| t.id()).collect::<Vec<uint>>(), vec![busy]);
+  }
+
+  /// Reminders wait for an old ticket, then don't repeat until the age has passed again
+  #[test]
+  fn reminder_throttling() {
+    let mut mgr = manager();
+    open(&mut mgr, "#test", "fred", "emotes");
+    mgr.last_reminder = UTC::now() - Duration::hours(1);
+    // nothing's old enough yet
+    assert!(!mgr.reminder_due());
+
+    mgr.tickets.get_mut(0).opened = UTC::now() - Duration::hours(1);
+    assert!(mgr.reminder_due());
+    assert!(!mgr.reminder_due());
+
+    mgr.last_reminder = UTC::now() - Duration::minutes(31);
+    assert!(mgr.reminder_due());
+  }
+
+  /// Each control channel only hears about its own channels' tickets
+  #[test]
+  fn reminder_scoped() {
+    let mut mgr = manager();
+    open(&mut mgr, "#a", "fred", "emotes");
+    open(&mut mgr, "#a", "bob", "emotes");
+    open(&mut mgr, "#b", "fred", "emotes");
+    mgr.tickets.get_mut(0).opened = UTC::now() - Duration::hours(1);
+    mgr.tickets.get_mut(2).opened = UTC::now() - Duration::minutes(5);
+
+    let reminder = mgr.reminder(|channel| channel == "#a").unwrap();
+    assert!(reminder.as_slice().starts_with("2 tickets waiting, the oldest (#1) is 1h old."));
+    // #b's only ticket is too new to nag about
+    assert!(mgr.reminder(|channel| channel == "#b").is_none());
+  }
+
+  #[test]
+  fn closed_with_channel() {
+    let mut mgr = manager();
+    open(&mut mgr, "#a", "fred", "emotes");
+    open(&mut mgr, "#b", "fred", "emotes");
+    assert_eq!(mgr.close_channel("#a").len(), 1);
+    assert_eq!(mgr.iter().map(|t| t.channel().to_string()).collect::<Vec<String>>(), vec!["#b".to_string()]);
   }
 }
@@ -1,48 +1,378 @@
 use chrono;
+use irc;
 use chrono::{
   DateTime,
+  Duration,
+  Offset,
   UTC
 };
+use history::History;
+use masks;
 use std::collections::HashMap;
+use userstore::{InfractionRecord, UserRecord, UserStore};
 
 pub struct UserState {
+  /// Infractions since their last ban.
   pub infractions: u32,
+  /// Every infraction they've had, oldest first.
+  pub history: Vec<InfractionRecord>,
 
   pub ban_expiration: Option<DateTime<UTC>>,
 
   pub last_message_time: DateTime<UTC>,
   pub last_message: String,
+  /// What they've said lately, in any channel.
+  pub recent: History,
+  /// nick!user@host from their last message, so mods can ban them by nick.
+  pub last_seen_as: Option<irc::User>,
 
   // consecutive "one word per line" messages
-  pub simple_msg_count: u32
+  pub simple_msg_count: u32,
+
+  /// Where they're kept in the store, once we know who they are. See `key_for`.
+  key: Option<String>
 }
 impl UserState {
-  pub fn new() -> UserState {
+  /// `history_len` is how many of their messages go in `recent`.
+  pub fn new(history_len: uint) -> UserState {
     UserState {
       infractions: 0,
+      history: Vec::new(),
 
       last_message: "".to_string(),
+      recent: History::new(history_len),
       last_message_time: chrono::UTC::now(),
+      last_seen_as: None,
 
       ban_expiration: None,
 
-      simple_msg_count: 0
+      simple_msg_count: 0,
+
+      key: None
     }
   }
+  /// Picks up where we left off with somebody, from the store.
+  /// Anything they did before we knew who they were still counts.
+  fn restore(&mut self, record: UserRecord) {
+    self.infractions += record.infractions;
+    let mut history = record.history;
+    history.push_all(self.history.as_slice());
+    self.history = history;
+    self.ban_expiration = record.ban_expiration.map(|secs| UTC.timestamp(secs, 0)).or(self.ban_expiration);
+  }
+  /// Where they picked up the infractions since their last ban.
+  pub fn infraction_channels(&self) -> Vec<String> {
+    let skip = self.history.len() - (self.infractions as uint).min(self.history.len());
+    let mut channels: Vec<String> = self.history.iter().skip(skip).map(|i| i.channel.clone()).collect();
+    channels.sort();
+    channels.dedup();
+    channels
+  }
+  /// Can we forget them without losing anything that matters?
+  /// Their infractions are safe if they're in the store.
+  fn is_forgettable(&self) -> bool {
+    self.infractions == 0 || self.key.is_some()
+  }
+  /// None if we don't know who they are well enough to remember them.
+  fn to_record(&self) -> Option<UserRecord> {
+    self.key.as_ref().map(|key| UserRecord {
+      key: key.clone(),
+      infractions: self.infractions,
+      ban_expiration: self.ban_expiration.map(|expires| expires.timestamp()),
+      history: self.history.clone()
+    })
+  }
+}
+
+/// What a user's remembered by: their QuakeNet account if their host gives it away
+/// (see `masks::account`), otherwise their host. Nicks are too easy to change.
+pub fn key_for(user: &irc::User) -> Option<String> {
+  user.host().map(|host| {
+    let host = String::from_utf8_lossy(host).into_string();
+    match masks::account(host.as_slice()) {
+      Some(account) => format!("account:{}", account),
+      None => format!("host:{}", host)
+    }
+  })
 }
 
 pub struct UserManager {
-  users: HashMap<String, UserState>
+  users: HashMap<String, UserState>,
+  store: Box<UserStore>,
+  /// Most users we'll keep around. Past this, the longest-idle ones are forgotten.
+  max_users: uint,
+  /// How many messages each user's `recent` holds.
+  history_len: uint,
+  /// Users who haven't talked for this long are forgotten on `evict`.
+  idle_ttl: Duration
 }
 impl UserManager {
-  pub fn new() -> UserManager {
-    UserManager { users: HashMap::new() }
+  pub fn new(store: Box<UserStore>, max_users: uint, history_len: uint, idle_ttl: Duration) -> UserManager {
+    UserManager {
+      users: HashMap::new(),
+      store: store,
+      max_users: max_users,
+      history_len: history_len,
+      idle_ttl: idle_ttl
+    }
+  }
+  /// How many users we're keeping track of.
+  pub fn len(&self) -> uint {
+    self.users.len()
+  }
+  pub fn max_users(&self) -> uint {
+    self.max_users
+  }
+  /// How many of them have infractions against them.
+  pub fn with_infractions(&self) -> uint {
+    self.users.iter().filter(|&(_, state)| state.infractions > 0).count()
+  }
+  /// Forgets users who've been quiet for a while. Anybody whose infractions
+  /// aren't in the store yet is kept, so nobody gets off by going quiet.
+  pub fn evict(&mut self) {
+    let cutoff = UTC::now() - self.idle_ttl;
+    let idle: Vec<String> = self.users.iter()
+      .filter(|&(_, state)| state.last_message_time < cutoff && state.is_forgettable())
+      .map(|(nick, _)| nick.clone())
+      .collect();
+    for nick in idle.iter() {
+      self.users.remove(nick);
+    }
+    if !idle.is_empty() {
+      debug!("Forgot {} idle users, {} left", idle.len(), self.users.len());
+    }
+  }
+  /// We're full: forgets the longest-idle users, down to nine tenths of the cap,
+  /// so we don't have to do this again for every new nick.
+  fn make_room(&mut self) {
+    let mut idle: Vec<(DateTime<UTC>, String)> = self.users.iter()
+      .filter(|&(_, state)| state.is_forgettable())
+      .map(|(nick, state)| (state.last_message_time, nick.clone()))
+      .collect();
+    idle.sort();
+    let excess = self.users.len() + 1 - self.max_users * 9 / 10;
+    for &(_, ref nick) in idle.iter().take(excess) {
+      self.users.remove(nick);
+    }
+    if self.users.len() >= self.max_users {
+      warn!("Tracking {} users, more than the cap of {}, but none of them can be forgotten", self.users.len(), self.max_users);
+    }
+  }
+  fn ensure_room(&mut self, nick: &str) {
+    if self.users.len() >= self.max_users && self.users.find_equiv(&nick).is_none() {
+      self.make_room();
+    }
+  }
+  pub fn find<'a>(&'a self, nick: &str) -> Option<&'a UserState> {
+    self.users.find_equiv(&nick)
   }
   /// Either gets existing UserState for a nick,
   /// or creates a new one for you.
   pub fn get_or_create<'a>(&'a mut self, nick: &str) -> &'a mut UserState {
+    self.ensure_room(nick);
     // no find_mut_equiv? ;_;
     let nick = nick.to_string();
-    self.users.find_or_insert_with(nick, |_| UserState::new())
+    let history_len = self.history_len;
+    self.users.find_or_insert_with(nick, |_| UserState::new(history_len))
+  }
+  /// Gets a user's state, noting where they were seen from.
+  /// The first time we see who's behind a nick, whatever the store
+  /// remembers about them is loaded.
+  pub fn seen<'a>(&'a mut self, user: &irc::User) -> &'a mut UserState {
+    let nick = String::from_utf8_lossy(user.nick()).into_string();
+    self.ensure_room(nick.as_slice());
+    let key = key_for(user);
+    let history_len = self.history_len;
+    let state = self.users.find_or_insert_with(nick, |_| UserState::new(history_len));
+    state.last_seen_as = Some(user.clone());
+
+    if key.is_some() && state.key != key {
+      match state.to_record() {
+        // somebody else has the nick now, or they've moved host;
+        // either way, what we had stays with the old key
+        Some(record) => {
+          self.store.save(&record);
+          *state = UserState::new(history_len);
+          state.last_seen_as = Some(user.clone());
+        },
+        None => ()
+      }
+      let unsaved = state.infractions > 0;
+      match self.store.load(key.as_ref().unwrap().as_slice()) {
+        Some(record) => state.restore(record),
+        None => ()
+      }
+      state.key = key;
+      if unsaved {
+        self.store.save(&state.to_record().unwrap());
+      }
+    }
+    state
+  }
+  /// Counts an infraction against a user, and remembers it.
+  /// Returns how many they've had since their last ban.
+  pub fn add_infraction(&mut self, user: &irc::User, channel: &str, reason: &str) -> u32 {
+    let infractions = {
+      let state = self.seen(user);
+      state.infractions += 1;
+      state.history.push(InfractionRecord {
+        time: UTC::now().timestamp(),
+        channel: channel.to_string(),
+        reason: reason.to_string()
+      });
+      state.infractions
+    };
+    self.save(String::from_utf8_lossy(user.nick()).as_slice());
+    infractions
+  }
+  /// Wipes the slate clean, in the store too. Their history is kept, though.
+  /// Returns false if they haven't talked lately, since then we can't tell
+  /// who's behind the nick to look them up.
+  pub fn forgive(&mut self, nick: &str) -> bool {
+    let user = match self.find(nick).and_then(|state| state.last_seen_as.clone()) {
+      Some(user) => user,
+      None => return false
+    };
+    self.seen(&user).infractions = 0;
+    self.save(nick);
+    true
+  }
+  /// They got banned, so their infractions start over.
+  pub fn banned(&mut self, user: &irc::User, expires: DateTime<UTC>) {
+    {
+      let state = self.seen(user);
+      state.infractions = 0;
+      state.ban_expiration = Some(expires);
+    }
+    self.save(String::from_utf8_lossy(user.nick()).as_slice());
+  }
+  fn save(&mut self, nick: &str) {
+    match self.users.find_equiv(&nick).and_then(|state| state.to_record()) {
+      Some(record) => self.store.save(&record),
+      None => debug!("Not saving {}, I don't know their host", nick)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use chrono::{Duration, UTC};
+  use irc;
+  use std::io::TempDir;
+  use userstore::{FlatFileStore, MemoryStore};
+  use super::{UserManager, key_for};
+
+  fn user(prefix: &str) -> irc::User {
+    irc::User::parse(prefix.as_bytes())
+  }
+
+  fn manager() -> UserManager {
+    UserManager::new(box MemoryStore::new(), 100, 10, Duration::hours(1))
+  }
+
+  #[test]
+  fn keys() {
+    assert_eq!(key_for(&user("fred!~fred@fred.users.quakenet.org")), Some("account:fred".to_string()));
+    assert_eq!(key_for(&user("fred!~fred@example.com")), Some("host:example.com".to_string()));
+    assert_eq!(key_for(&user("fred")), None);
+  }
+
+  /// Infractions follow the person, not the nick
+  #[test]
+  fn remembered_by_host() {
+    let mut mgr = manager();
+    let fred = user("fred!~fred@example.com");
+    mgr.add_infraction(&fred, "#test", "kappa");
+    assert_eq!(mgr.add_infraction(&fred, "#test", "kappa"), 2);
+
+    // fred comes back as freddy, and somebody else takes "fred"
+    assert_eq!(mgr.seen(&user("freddy!~fred@example.com")).infractions, 2);
+    assert_eq!(mgr.seen(&user("fred!~bob@example.org")).infractions, 0);
+    assert_eq!(mgr.find("freddy").unwrap().history.len(), 2);
+  }
+
+  /// Changing host doesn't lose anything: the old host keeps its record,
+  /// and infractions from before we knew their host still count
+  #[test]
+  fn host_changes() {
+    let mut mgr = manager();
+    let home = user("fred!~fred@example.com");
+    mgr.add_infraction(&home, "#test", "kappa");
+
+    // fred authes with Q and gets an account host
+    assert_eq!(mgr.seen(&user("fred!~fred@fred.users.quakenet.org")).infractions, 0);
+    assert_eq!(mgr.seen(&home).infractions, 1);
+
+    mgr.get_or_create("nohost").infractions = 1;
+    assert_eq!(mgr.seen(&user("nohost!~nohost@example.net")).infractions, 1);
+    mgr.add_infraction(&user("nohost!~nohost@example.net"), "#test", "kappa");
+    // a fresh nick from the same host picks up both
+    assert_eq!(mgr.seen(&user("other!~nohost@example.net")).infractions, 2);
+  }
+
+  /// Forgiving sticks across restarts, and needs to know who's behind the nick
+  #[test]
+  fn forgiven_for_good() {
+    let dir = TempDir::new("nofunbot").unwrap();
+    let path = dir.path().join("users.json");
+    let session = || UserManager::new(box FlatFileStore::open(path.clone()), 100, 10, Duration::hours(1));
+    let fred = user("fred!~fred@example.com");
+
+    let mut mgr = session();
+    mgr.add_infraction(&fred, "#test", "kappa");
+    mgr.add_infraction(&fred, "#test", "kappa");
+
+    // after a restart, fred hasn't talked yet
+    let mut mgr = session();
+    assert!(!mgr.forgive("fred"));
+    mgr.seen(&fred);
+    assert!(mgr.forgive("fred"));
+
+    let mut mgr = session();
+    assert_eq!(mgr.seen(&fred).infractions, 0);
+    assert_eq!(mgr.find("fred").unwrap().history.len(), 2);
+  }
+
+  /// Only infractions since the last ban say where somebody's in trouble
+  #[test]
+  fn infraction_channels() {
+    let mut mgr = manager();
+    let fred = user("fred!~fred@example.com");
+    mgr.add_infraction(&fred, "#old", "kappa");
+    mgr.banned(&fred, UTC::now());
+    mgr.add_infraction(&fred, "#b", "kappa");
+    mgr.add_infraction(&fred, "#a", "kappa");
+    mgr.add_infraction(&fred, "#b", "kappa");
+    assert_eq!(mgr.find("fred").unwrap().infraction_channels(), vec!["#a".to_string(), "#b".to_string()]);
+  }
+
+  /// Quiet users are forgotten, unless we'd lose their infractions
+  #[test]
+  fn idle_eviction() {
+    let mut mgr = manager();
+    let long_ago = UTC::now() - Duration::hours(2);
+    mgr.get_or_create("quiet").last_message_time = long_ago;
+    mgr.get_or_create("chatty");
+    mgr.get_or_create("nohost").infractions = 1;
+    mgr.get_or_create("nohost").last_message_time = long_ago;
+
+    mgr.evict();
+    assert!(mgr.find("quiet").is_none());
+    assert!(mgr.find("chatty").is_some());
+    assert!(mgr.find("nohost").is_some());
+  }
+
+  /// Past the cap, the longest-idle users make way
+  #[test]
+  fn capped() {
+    let mut mgr = UserManager::new(box MemoryStore::new(), 10, 10, Duration::hours(1));
+    for i in range(0i64, 10) {
+      mgr.get_or_create(format!("user{}", i).as_slice()).last_message_time = UTC::now() - Duration::minutes(10 - i);
+    }
+    mgr.get_or_create("newbie");
+    assert!(mgr.len() <= 10);
+    assert!(mgr.find("user0").is_none());
+    assert!(mgr.find("user9").is_some());
+    assert!(mgr.find("newbie").is_some());
   }
 }
@@ -0,0 +1,296 @@
+use files;
+use serialize::json;
+use sqlite3;
+use sqlite3::types::{Integer64, Text, Null, SQLITE_DONE, SQLITE_ROW};
+use std::collections::HashMap;
+use std::io::File;
+
+/// Which kind of store user records are kept in.
+#[deriving(Clone, PartialEq, Show, Decodable)]
+pub enum StoreKind {
+  /// One JSON file, rewritten whenever a record changes. Fine for a few thousand users.
+  FlatFile,
+  /// An SQLite database, for when the flat file gets too big to rewrite every time.
+  Sqlite
+}
+
+/// One infraction, as it's remembered.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct InfractionRecord {
+  /// Seconds since the epoch.
+  pub time: i64,
+  pub channel: String,
+  pub reason: String
+}
+
+/// What we keep about a user between restarts.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct UserRecord {
+  /// "account:fred" or "host:example.com", see `usermanager::key_for`.
+  pub key: String,
+  /// Infractions since their last ban.
+  pub infractions: u32,
+  /// Seconds since the epoch.
+  pub ban_expiration: Option<i64>,
+  /// Every infraction, oldest first.
+  pub history: Vec<InfractionRecord>
+}
+
+pub trait UserStore {
+  fn load(&mut self, key: &str) -> Option<UserRecord>;
+  fn save(&mut self, record: &UserRecord);
+}
+
+/// Opens the configured store. Falls back to keeping records in memory
+/// if it can't be opened, so moderation carries on either way.
+pub fn open(kind: StoreKind, path: Path) -> Box<UserStore> {
+  match kind {
+    FlatFile => box FlatFileStore::open(path) as Box<UserStore>,
+    Sqlite => match SqliteStore::open(&path) {
+      Ok(store) => box store as Box<UserStore>,
+      Err(err) => {
+        error!("Couldn't open user database {}, keeping users in memory: {}", path.display(), err);
+        box MemoryStore::new() as Box<UserStore>
+      }
+    }
+  }
+}
+
+/// Forgets everything on restart. For when the real store is broken.
+pub struct MemoryStore {
+  records: HashMap<String, UserRecord>
+}
+
+impl MemoryStore {
+  pub fn new() -> MemoryStore {
+    MemoryStore { records: HashMap::new() }
+  }
+}
+
+impl UserStore for MemoryStore {
+  fn load(&mut self, key: &str) -> Option<UserRecord> {
+    self.records.find_equiv(&key).map(|record| record.clone())
+  }
+  fn save(&mut self, record: &UserRecord) {
+    self.records.insert(record.key.clone(), record.clone());
+  }
+}
+
+/// Every record in one JSON file, read once at startup.
+pub struct FlatFileStore {
+  path: Path,
+  records: HashMap<String, UserRecord>
+}
+
+impl FlatFileStore {
+  pub fn open(path: Path) -> FlatFileStore {
+    let mut store = FlatFileStore { path: path, records: HashMap::new() };
+    store.read();
+    store
+  }
+  fn read(&mut self) {
+    let contents = match File::open(&self.path).read_to_string() {
+      Ok(contents) => contents,
+      Err(err) => {
+        warn!("Couldn't read users from {}, starting with none: {}", self.path.display(), err);
+        return;
+      }
+    };
+    match json::decode::<Vec<UserRecord>>(contents.as_slice()) {
+      Ok(records) => {
+        self.records = records.move_iter().map(|record| (record.key.clone(), record)).collect();
+        info!("Loaded {} users from {}", self.records.len(), self.path.display());
+      },
+      Err(err) => error!("User store {} is corrupt, ignoring it: {}", self.path.display(), err)
+    }
+  }
+  /// Rewrites the whole file.
+  fn write(&self) {
+    let records: Vec<&UserRecord> = self.records.iter().map(|(_, record)| record).collect();
+    match files::replace(&self.path, json::encode(&records).as_slice()) {
+      Ok(()) => (),
+      Err(err) => error!("Couldn't save users to {}: {}", self.path.display(), err)
+    }
+  }
+}
+
+impl UserStore for FlatFileStore {
+  fn load(&mut self, key: &str) -> Option<UserRecord> {
+    self.records.find_equiv(&key).map(|record| record.clone())
+  }
+  fn save(&mut self, record: &UserRecord) {
+    self.records.insert(record.key.clone(), record.clone());
+    self.write();
+  }
+}
+
+static SCHEMA: &'static str = "
+  CREATE TABLE IF NOT EXISTS users (
+    key TEXT PRIMARY KEY,
+    infractions INTEGER NOT NULL,
+    ban_expiration INTEGER
+  );
+  CREATE TABLE IF NOT EXISTS infractions (
+    key TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    channel TEXT NOT NULL,
+    reason TEXT NOT NULL
+  );
+  CREATE INDEX IF NOT EXISTS infractions_key ON infractions (key);";
+
+/// Records in an SQLite database, one row per user and one per infraction.
+pub struct SqliteStore {
+  db: sqlite3::Database
+}
+
+impl SqliteStore {
+  pub fn open(path: &Path) -> Result<SqliteStore, String> {
+    let path = match path.as_str() {
+      Some(path) => path,
+      None => return Err("the path isn't valid UTF-8".to_string())
+    };
+    let mut db = match sqlite3::open(path) {
+      Ok(db) => db,
+      Err(err) => return Err(format!("{}", err))
+    };
+    match db.exec(SCHEMA) {
+      Ok(_) => Ok(SqliteStore { db: db }),
+      Err(err) => Err(format!("{}", err))
+    }
+  }
+  /// Runs a statement that doesn't return rows. Err holds SQLite's complaint.
+  fn run(&self, sql: &str, params: &[sqlite3::types::BindArg]) -> Result<(), String> {
+    let mut cursor = match self.db.prepare(sql, &None) {
+      Ok(cursor) => cursor,
+      Err(err) => return Err(format!("{}", err))
+    };
+    cursor.bind_params(params);
+    match cursor.step() {
+      SQLITE_DONE => Ok(()),
+      code => Err(format!("{}: {}", code, self.db.get_errmsg()))
+    }
+  }
+  fn history(&self, key: &str) -> Vec<InfractionRecord> {
+    let mut history = Vec::new();
+    let mut cursor = match self.db.prepare("SELECT time, channel, reason FROM infractions \
+                                            WHERE key = ? ORDER BY time, rowid", &None) {
+      Ok(cursor) => cursor,
+      Err(err) => {
+        error!("Couldn't read infractions for {}: {}", key, err);
+        return history;
+      }
+    };
+    cursor.bind_params([Text(key.to_string())]);
+    while cursor.step() == SQLITE_ROW {
+      history.push(InfractionRecord {
+        time: cursor.get_i64(0),
+        channel: cursor.get_text(1).unwrap_or(String::new()),
+        reason: cursor.get_text(2).unwrap_or(String::new())
+      });
+    }
+    history
+  }
+  fn write(&mut self, record: &UserRecord) -> Result<(), String> {
+    let expiration = match record.ban_expiration {
+      Some(secs) => Integer64(secs),
+      None => Null
+    };
+    try!(self.run("INSERT OR REPLACE INTO users (key, infractions, ban_expiration) VALUES (?, ?, ?)",
+                  [Text(record.key.clone()), Integer64(record.infractions as i64), expiration]));
+    // history only ever grows, so only the new entries need writing
+    let stored = self.history(record.key.as_slice()).len();
+    for entry in record.history.iter().skip(stored) {
+      try!(self.run("INSERT INTO infractions (key, time, channel, reason) VALUES (?, ?, ?, ?)",
+                    [Text(record.key.clone()), Integer64(entry.time),
+                     Text(entry.channel.clone()), Text(entry.reason.clone())]));
+    }
+    Ok(())
+  }
+}
+
+impl UserStore for SqliteStore {
+  fn load(&mut self, key: &str) -> Option<UserRecord> {
+    let (infractions, ban_expiration) = {
+      let mut cursor = match self.db.prepare("SELECT infractions, ban_expiration FROM users WHERE key = ?", &None) {
+        Ok(cursor) => cursor,
+        Err(err) => {
+          error!("Couldn't read user {}: {}", key, err);
+          return None;
+        }
+      };
+      cursor.bind_params([Text(key.to_string())]);
+      if cursor.step() != SQLITE_ROW {
+        return None;
+      }
+      let expiration = match cursor.get_column_type(1) {
+        sqlite3::types::SQLITE_NULL => None,
+        _ => Some(cursor.get_i64(1))
+      };
+      (cursor.get_i64(0) as u32, expiration)
+    };
+    Some(UserRecord {
+      key: key.to_string(),
+      infractions: infractions,
+      ban_expiration: ban_expiration,
+      history: self.history(key)
+    })
+  }
+  fn save(&mut self, record: &UserRecord) {
+    match self.db.exec("BEGIN").map_err(|err| format!("{}", err))
+      .and_then(|_| self.write(record)) {
+      Ok(()) => {
+        self.db.exec("COMMIT").ok();
+      },
+      Err(err) => {
+        error!("Couldn't save user {}: {}", record.key, err);
+        self.db.exec("ROLLBACK").ok();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::TempDir;
+  use super::{FlatFileStore, SqliteStore, UserStore, UserRecord, InfractionRecord};
+
+  fn record() -> UserRecord {
+    UserRecord {
+      key: "account:fred".to_string(),
+      infractions: 2,
+      ban_expiration: Some(1400000000),
+      history: vec![InfractionRecord { time: 1300000000, channel: "#test".to_string(), reason: "kappa".to_string() },
+                    InfractionRecord { time: 1300000060, channel: "#test".to_string(), reason: "kappa".to_string() }]
+    }
+  }
+
+  /// Whatever goes in comes back out, even after a restart
+  fn round_trip(store: &mut UserStore) {
+    assert!(store.load("account:fred").is_none());
+    let mut fred = record();
+    store.save(&fred);
+    assert_eq!(store.load("account:fred"), Some(fred.clone()));
+
+    fred.infractions = 0;
+    fred.ban_expiration = None;
+    fred.history.push(InfractionRecord { time: 1300000120, channel: "#other".to_string(), reason: "doge".to_string() });
+    store.save(&fred);
+    assert_eq!(store.load("account:fred"), Some(fred));
+  }
+
+  #[test]
+  fn flat_file() {
+    let dir = TempDir::new("nofunbot").unwrap();
+    let path = dir.path().join("users.json");
+    round_trip(&mut FlatFileStore::open(path.clone()));
+    assert_eq!(FlatFileStore::open(path).load("account:fred").unwrap().history.len(), 3);
+  }
+
+  #[test]
+  fn sqlite() {
+    let dir = TempDir::new("nofunbot").unwrap();
+    let path = dir.path().join("users.db");
+    round_trip(&mut SqliteStore::open(&path).unwrap());
+    assert_eq!(SqliteStore::open(&path).unwrap().load("account:fred").unwrap().history.len(), 3);
+  }
+}